@@ -0,0 +1,817 @@
+//! Admin API for inspecting and controlling a running stub server
+
+use std::sync::atomic::Ordering;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use maplit::hashmap;
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::JSON;
+use pact_models::pact::load_pact_from_json;
+use pact_models::prelude::v4::*;
+use pact_models::query_strings::build_query_string;
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::interaction::V4Interaction;
+use pact_models::v4::V4InteractionType;
+use regex::Regex;
+use serde_json::{json, Value};
+use percent_encoding::percent_decode_str;
+use tracing::{error, info, warn};
+
+use crate::journal::JournalFilter;
+use crate::messages::{self, MessageSelector, SyncMessageMatch};
+use crate::{plugins, PactSource};
+use crate::server::Shared;
+
+/// Returns the path relative to the admin prefix if the request path falls under it
+pub fn admin_path<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+  path.strip_prefix(prefix)
+    .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Handles a request made to the admin API. The path is relative to the admin prefix.
+pub async fn handle_admin_request(shared: &Shared, request: &HttpRequest, path: &str) -> HttpResponse {
+  let method = request.method.to_uppercase();
+  let path = path.trim_end_matches('/');
+  match (method.as_str(), path) {
+    ("GET", "/pacts") => json_response(200, pacts_json(&shared.current_sources())),
+    ("POST", "/pacts") => upload_pact(shared, request),
+    (_, "/pacts") => method_not_allowed(&method, path),
+    ("DELETE", _) if path.starts_with("/pacts/") => remove_pact(shared, &path["/pacts/".len()..]),
+    (_, _) if path.starts_with("/pacts/") => method_not_allowed(&method, path),
+    ("GET", "/requests") => list_requests(shared, request),
+    ("DELETE", "/requests") => clear_requests(shared),
+    (_, "/requests") => method_not_allowed(&method, path),
+    ("GET", "/state") => provider_state(shared),
+    ("POST", "/state") => set_provider_state(shared, request),
+    ("DELETE", "/state") => reset_provider_state(shared),
+    (_, "/state") => method_not_allowed(&method, path),
+    ("GET", "/scenario") => scenario_state(shared),
+    ("PUT", "/scenario") => set_scenario_state(shared, request),
+    ("DELETE", "/scenario") => reset_scenario(shared),
+    (_, "/scenario") => method_not_allowed(&method, path),
+    ("GET", "/sequences") => sequences(shared),
+    ("DELETE", "/sequences") => reset_sequences(shared),
+    (_, "/sequences") => method_not_allowed(&method, path),
+    ("POST", "/trigger") => trigger_message(shared, request).await,
+    (_, "/trigger") => method_not_allowed(&method, path),
+    ("POST", _) if path.starts_with("/messages/") => sync_message(shared, request, &path["/messages/".len()..]).await,
+    (_, _) if path.starts_with("/messages/") => method_not_allowed(&method, path),
+    _ => json_response(404, json!({ "error": format!("No admin endpoint found for path {}", path) }))
+  }
+}
+
+fn upload_pact(shared: &Shared, request: &HttpRequest) -> HttpResponse {
+  let Some(shared_sources) = &shared.shared_sources else {
+    return json_response(500, json!({ "error": "Pacts can not be modified on this server" }));
+  };
+
+  let json = match request.body.value() {
+    Some(body) => match serde_json::from_slice::<Value>(&body) {
+      Ok(json) => json,
+      Err(err) => return json_response(400, json!({ "error": format!("Request body is not valid JSON: {}", err) }))
+    },
+    None => return json_response(400, json!({ "error": "Request body must contain a pact" }))
+  };
+  let pact = match load_pact_from_json("uploaded pact", &json).and_then(|pact| pact.as_v4_pact()) {
+    Ok(pact) => pact,
+    Err(err) => return json_response(400, json!({ "error": format!("Failed to load pact: {}", err) }))
+  };
+  let warning = plugins::plugin_transport_warning(&pact);
+  if let Some(warning) = &warning {
+    warn!("{}", warning);
+  }
+
+  let id = request.query.as_ref()
+    .and_then(|query| query.get("id"))
+    .and_then(|values| values.first().cloned().flatten());
+  if matches!(&id, Some(id) if id.is_empty() || id.contains('/')) {
+    return json_response(400, json!({ "error": "The id of an uploaded pact must not be empty or contain a '/'" }));
+  }
+
+  let mut sources = shared_sources.lock().unwrap();
+  let id = id.unwrap_or_else(|| next_upload_id(shared, &sources));
+  let interactions = pact.interactions.len();
+  info!("Adding uploaded pact '{}' between {} and {} ({} interactions)", id, pact.consumer.name,
+    pact.provider.name, interactions);
+
+  let replaced = remove_uploaded(&mut sources, &id);
+  sources.push((pact.clone(), PactSource::Uploaded(id.clone())));
+  let mut json = json!({
+    "id": id,
+    "consumer": pact.consumer.name,
+    "provider": pact.provider.name,
+    "interactions": interactions
+  });
+  if let Some(warning) = warning {
+    json["warning"] = json!(warning);
+  }
+  json_response(if replaced { 200 } else { 201 }, json)
+}
+
+/// Next generated id for an uploaded pact, skipping any ids already given to uploaded pacts
+fn next_upload_id(shared: &Shared, sources: &[(V4Pact, PactSource)]) -> String {
+  loop {
+    let id = (shared.upload_count.fetch_add(1, Ordering::SeqCst) + 1).to_string();
+    if !sources.iter().any(|(_, source)| matches!(source, PactSource::Uploaded(uploaded_id) if *uploaded_id == id)) {
+      return id;
+    }
+  }
+}
+
+fn remove_pact(shared: &Shared, id: &str) -> HttpResponse {
+  let id = percent_decode_str(id).decode_utf8_lossy();
+  let removed = match &shared.shared_sources {
+    Some(shared_sources) => remove_uploaded(&mut shared_sources.lock().unwrap(), &id),
+    None => false
+  };
+  if removed {
+    info!("Removed uploaded pact '{}'", id);
+    HttpResponse { status: 204, .. HttpResponse::default() }
+  } else {
+    json_response(404, json!({ "error": format!("No uploaded pact found with id '{}'", id) }))
+  }
+}
+
+fn remove_uploaded(sources: &mut Vec<(V4Pact, PactSource)>, id: &str) -> bool {
+  let count = sources.len();
+  sources.retain(|(_, source)| !matches!(source, PactSource::Uploaded(uploaded_id) if uploaded_id == id));
+  sources.len() != count
+}
+
+fn list_requests(shared: &Shared, request: &HttpRequest) -> HttpResponse {
+  let Some(journal) = &shared.journal else {
+    return json_response(500, json!({ "error": "Requests are not being recorded on this server" }));
+  };
+
+  let param = |name: &str| request.query.as_ref()
+    .and_then(|query| query.get(name))
+    .and_then(|values| values.first().cloned().flatten());
+  let time_param = |name: &str| match param(name) {
+    Some(value) => DateTime::parse_from_rfc3339(&value)
+      .map(|time| Some(time.with_timezone(&Utc)))
+      .map_err(|err| format!("Query parameter '{}' is not a valid RFC 3339 timestamp: {}", name, err)),
+    None => Ok(None)
+  };
+  let filter = match (time_param("since"), time_param("until")) {
+    (Ok(since), Ok(until)) => JournalFilter { path: param("path"), method: param("method"), since, until },
+    (Err(err), _) | (_, Err(err)) => return json_response(400, json!({ "error": err }))
+  };
+
+  let entries = journal.entries(&filter).iter().map(|entry| entry.to_json()).collect::<Vec<_>>();
+  json_response(200, json!({ "requests": entries }))
+}
+
+fn clear_requests(shared: &Shared) -> HttpResponse {
+  if let Some(journal) = &shared.journal {
+    info!("Clearing the request journal");
+    journal.clear();
+  }
+  HttpResponse { status: 204, .. HttpResponse::default() }
+}
+
+fn provider_state(shared: &Shared) -> HttpResponse {
+  let provider_state = shared.provider_state.lock().unwrap().as_ref().map(|regex| regex.to_string());
+  json_response(200, json!({
+    "providerState": provider_state,
+    "initialProviderState": shared.options.provider_state.as_ref().map(|regex| regex.to_string())
+  }))
+}
+
+fn set_provider_state(shared: &Shared, request: &HttpRequest) -> HttpResponse {
+  if shared.scenario.is_some() {
+    return json_response(409, json!({
+      "error": "The provider state can not be set in scenario mode, use PUT /scenario to change the scenario state instead"
+    }));
+  }
+  let json = request.body.value()
+    .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+    .unwrap_or_default();
+  let provider_state = match json.get("state") {
+    Some(Value::String(state)) => match Regex::new(state) {
+      Ok(regex) => Some(regex),
+      Err(err) => return json_response(400, json!({
+        "error": format!("'{}' is not a valid regular expression: {}", state, err)
+      }))
+    },
+    Some(Value::Null) => None,
+    _ => return json_response(400, json!({
+      "error": "Request body must be a JSON object with a 'state' regular expression, or null to not filter by provider state"
+    }))
+  };
+  info!("Setting the provider state filter to {:?}", provider_state.as_ref().map(|regex| regex.as_str()));
+  *shared.provider_state.lock().unwrap() = provider_state;
+  self::provider_state(shared)
+}
+
+fn reset_provider_state(shared: &Shared) -> HttpResponse {
+  info!("Resetting the provider state filter");
+  *shared.provider_state.lock().unwrap() = shared.options.provider_state.clone();
+  provider_state(shared)
+}
+
+fn scenario_state(shared: &Shared) -> HttpResponse {
+  match &shared.scenario {
+    Some(scenario) => json_response(200, json!({
+      "state": scenario.current_state(),
+      "initialState": scenario.initial_state()
+    })),
+    None => json_response(404, json!({ "error": "Scenario mode is not enabled on this server" }))
+  }
+}
+
+fn set_scenario_state(shared: &Shared, request: &HttpRequest) -> HttpResponse {
+  let Some(scenario) = &shared.scenario else {
+    return json_response(404, json!({ "error": "Scenario mode is not enabled on this server" }));
+  };
+  let state = request.body.value()
+    .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+    .and_then(|json| json.get("state").and_then(|state| state.as_str()).map(|state| state.to_string()));
+  match state {
+    Some(state) => {
+      scenario.set_state(state);
+      scenario_state(shared)
+    }
+    None => json_response(400, json!({ "error": "Request body must be a JSON object with a 'state' string" }))
+  }
+}
+
+fn reset_scenario(shared: &Shared) -> HttpResponse {
+  match &shared.scenario {
+    Some(scenario) => {
+      scenario.reset();
+      scenario_state(shared)
+    }
+    None => json_response(404, json!({ "error": "Scenario mode is not enabled on this server" }))
+  }
+}
+
+fn sequences(shared: &Shared) -> HttpResponse {
+  let selector = &shared.matching.selector;
+  json_response(200, json!({
+    "mode": selector.sequence().map(|mode| mode.to_string()),
+    "strategy": selector.strategy().to_string(),
+    "positions": selector.positions()
+  }))
+}
+
+fn reset_sequences(shared: &Shared) -> HttpResponse {
+  info!("Resetting the interaction sequences");
+  shared.matching.selector.reset();
+  sequences(shared)
+}
+
+async fn trigger_message(shared: &Shared, request: &HttpRequest) -> HttpResponse {
+  let selector = request.body.value()
+    .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+    .ok_or_else(|| anyhow!("Request body must be a JSON object"))
+    .and_then(|json| MessageSelector::from_json(&json));
+  let selector = match selector {
+    Ok(selector) => selector,
+    Err(err) => return json_response(400, json!({ "error": err.to_string() }))
+  };
+  let Some(message) = messages::find_message(&shared.current_sources(), &selector) else {
+    return json_response(404, json!({ "error": format!("No message interaction found for {:?}", selector) }));
+  };
+
+  let contents = messages::generate_contents(&message).await;
+  if let Some(push) = &shared.websocket {
+    push.push(&message.description, &contents);
+  }
+  match &shared.message_callback {
+    Some(callback) => match callback.deliver(&message.description, &contents).await {
+      Ok(status) => json_response(200, messages::delivery_json(&message.description, callback, status)),
+      Err(err) => {
+        error!("{:#}", err);
+        json_response(502, json!({ "error": format!("{:#}", err) }))
+      }
+    },
+    None => messages::message_response(&contents)
+  }
+}
+
+async fn sync_message(shared: &Shared, request: &HttpRequest, description: &str) -> HttpResponse {
+  let description = percent_decode_str(description).decode_utf8_lossy();
+  let contents = match messages::request_contents(request) {
+    Ok(contents) => contents,
+    Err(err) => return json_response(400, json!({ "error": err.to_string() }))
+  };
+  match messages::match_sync_message(&shared.current_sources(), &description, &contents).await {
+    SyncMessageMatch::Matched(message) => {
+      info!("Request matched synchronous message '{}'", description);
+      messages::sync_message_response(&message).await
+    }
+    SyncMessageMatch::Mismatched(candidates) => {
+      warn!("Request did not match synchronous message '{}'", description);
+      json_response(404, json!({
+        "error": format!("Request did not match the synchronous message '{}'", description),
+        "candidates": candidates.iter().map(|(message, mismatches)| json!({
+          "key": message.unique_key(),
+          "providerStates": message.provider_states.iter().map(|ps| ps.name.clone()).collect::<Vec<_>>(),
+          "mismatches": mismatches.iter().map(|mismatch| json!({
+            "type": mismatch.mismatch_type(),
+            "description": mismatch.description()
+          })).collect::<Vec<_>>()
+        })).collect::<Vec<_>>()
+      }))
+    }
+    SyncMessageMatch::NotFound => json_response(404, json!({
+      "error": format!("No synchronous message interaction found with description '{}'", description)
+    }))
+  }
+}
+
+fn pacts_json(sources: &[(V4Pact, PactSource)]) -> Value {
+  let pacts = sources.iter()
+    .map(|(pact, source)| json!({
+      "consumer": pact.consumer.name,
+      "provider": pact.provider.name,
+      "source": source_json(source),
+      "interactions": pact.filter_interactions(V4InteractionType::Synchronous_HTTP)
+        .iter()
+        .filter_map(|i| i.as_v4_http())
+        .map(|i| interaction_json(&i))
+        .collect::<Vec<_>>()
+    }))
+    .collect::<Vec<_>>();
+  json!({ "pacts": pacts })
+}
+
+fn interaction_json(interaction: &SynchronousHttp) -> Value {
+  let request = &interaction.request;
+  json!({
+    "key": interaction.unique_key(),
+    "description": interaction.description,
+    "providerStates": interaction.provider_states.iter().map(|ps| ps.to_json()).collect::<Vec<_>>(),
+    "request": {
+      "method": request.method,
+      "path": request.path,
+      "query": request.query,
+      "headers": request.headers
+    },
+    "signature": request_signature(request)
+  })
+}
+
+/// Short form of the request that identifies an interaction, e.g. `GET /users?page=1`
+pub fn request_signature(request: &HttpRequest) -> String {
+  match &request.query {
+    Some(query) if !query.is_empty() =>
+      format!("{} {}?{}", request.method.to_uppercase(), request.path, build_query_string(query.clone())),
+    _ => format!("{} {}", request.method.to_uppercase(), request.path)
+  }
+}
+
+pub(crate) fn source_json(source: &PactSource) -> Value {
+  match source {
+    PactSource::File(path) => json!({ "type": "file", "path": path }),
+    PactSource::Dir(path) => json!({ "type": "dir", "path": path }),
+    PactSource::URL(url, _) => json!({ "type": "url", "url": url }),
+    PactSource::Broker { url, .. } => json!({ "type": "broker", "url": url }),
+    PactSource::Uploaded(id) => json!({ "type": "uploaded", "id": id }),
+    PactSource::Embedded => json!({ "type": "embedded" }),
+    PactSource::Unknown => json!({ "type": "unknown" })
+  }
+}
+
+fn method_not_allowed(method: &str, path: &str) -> HttpResponse {
+  json_response(405, json!({ "error": format!("Method {} is not supported for admin endpoint {}", method, path) }))
+}
+
+pub(crate) fn json_response(status: u16, body: Value) -> HttpResponse {
+  HttpResponse {
+    status,
+    headers: Some(hashmap!{ "Content-Type".to_string() => vec![JSON.to_string()] }),
+    body: OptionalBody::Present(body.to_string().into(), Some(JSON.clone()), None),
+    .. HttpResponse::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::prelude::*;
+  use pact_models::v4::async_message::AsynchronousMessage;
+  use pact_models::v4::message_parts::MessageContents;
+  use reqwest::Url;
+
+  use crate::journal::JournalEntry;
+  use crate::server::{handle_request, Reply, ServerHandler, ServerOptions};
+  use crate::strategy::SequenceMode;
+  use crate::StubServerBuilder;
+
+  use super::*;
+
+  fn admin_handler(sources: Vec<(V4Pact, PactSource)>) -> ServerHandler {
+    ServerHandler::new(sources, ServerOptions { admin_prefix: Some("/__pact".to_string()), .. ServerOptions::default() })
+  }
+
+  fn body_json(response: &HttpResponse) -> Value {
+    serde_json::from_slice(&response.body.value().unwrap_or_default()).unwrap()
+  }
+
+  #[test]
+  fn admin_path_only_matches_whole_segments() {
+    expect!(admin_path("/__pact", "/__pact")).to(be_some().value(""));
+    expect!(admin_path("/__pact", "/__pact/pacts")).to(be_some().value("/pacts"));
+    expect!(admin_path("/__pact", "/__pactual")).to(be_none());
+    expect!(admin_path("/__pact", "/users")).to(be_none());
+  }
+
+  #[test]
+  fn request_signature_includes_the_query_string() {
+    let request = HttpRequest {
+      method: "get".to_string(),
+      path: "/users".to_string(),
+      query: Some(hashmap!{ "page".to_string() => vec![Some("2".to_string())] }),
+      .. HttpRequest::default()
+    };
+    expect!(request_signature(&request)).to(be_equal_to("GET /users?page=2"));
+    expect!(request_signature(&HttpRequest::default())).to(be_equal_to("GET /"));
+  }
+
+  #[tokio::test]
+  async fn lists_the_loaded_pacts_and_interactions() {
+    let interaction = SynchronousHttp {
+      description: "get a user".to_string(),
+      provider_states: vec![ ProviderState::default("user exists") ],
+      request: HttpRequest { path: "/users/1".to_string(), .. HttpRequest::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      consumer: Consumer { name: "consumer".to_string() },
+      provider: Provider { name: "provider".to_string() },
+      interactions: vec![ interaction.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let request = HttpRequest { path: "/__pact/pacts".to_string(), .. HttpRequest::default() };
+
+    let handler = admin_handler(vec![(pact, PactSource::File("pact.json".to_string()))]);
+    let response = handle_admin_request(&handler.shared, &request, "/pacts").await;
+    expect!(response.status).to(be_equal_to(200));
+    let json = body_json(&response);
+    expect!(json["pacts"][0]["consumer"].clone()).to(be_equal_to(json!("consumer")));
+    expect!(json["pacts"][0]["source"].clone()).to(be_equal_to(json!({ "type": "file", "path": "pact.json" })));
+    let interaction = &json["pacts"][0]["interactions"][0];
+    expect!(interaction["description"].clone()).to(be_equal_to(json!("get a user")));
+    expect!(interaction["providerStates"][0]["name"].clone()).to(be_equal_to(json!("user exists")));
+    expect!(interaction["signature"].clone()).to(be_equal_to(json!("GET /users/1")));
+  }
+
+  #[tokio::test]
+  async fn returns_not_found_for_unknown_admin_paths() {
+    let request = HttpRequest { path: "/__pact/other".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&admin_handler(vec![]).shared, &request, "/other").await;
+    expect!(response.status).to(be_equal_to(404));
+  }
+
+  #[tokio::test]
+  async fn uploads_and_removes_pacts() {
+    let handler = admin_handler(vec![(V4Pact::default(), PactSource::Unknown)]);
+    let pact_json = json!({
+      "consumer": { "name": "uploaded-consumer" },
+      "provider": { "name": "uploaded-provider" },
+      "interactions": [{
+        "type": "Synchronous/HTTP",
+        "description": "uploaded interaction",
+        "request": { "method": "GET", "path": "/uploaded" },
+        "response": { "status": 200 }
+      }],
+      "metadata": { "pactSpecification": { "version": "4.0" } }
+    });
+    let upload = HttpRequest {
+      method: "POST".to_string(),
+      path: "/__pact/pacts".to_string(),
+      body: OptionalBody::Present(pact_json.to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let response = handle_admin_request(&handler.shared, &upload, "/pacts").await;
+    expect!(response.status).to(be_equal_to(201));
+    let id = body_json(&response)["id"].as_str().unwrap().to_string();
+    let sources = handler.shared.current_sources();
+    expect!(sources.len()).to(be_equal_to(2));
+    expect!(sources[1].0.consumer.name.clone()).to(be_equal_to("uploaded-consumer"));
+
+    let delete = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &delete, format!("/pacts/{}", id).as_str()).await;
+    expect!(response.status).to(be_equal_to(204));
+    expect!(handler.shared.current_sources().len()).to(be_equal_to(1));
+
+    let response = handle_admin_request(&handler.shared, &delete, format!("/pacts/{}", id).as_str()).await;
+    expect!(response.status).to(be_equal_to(404));
+  }
+
+  #[tokio::test]
+  async fn generated_upload_ids_skip_the_ids_given_by_the_user() {
+    let handler = admin_handler(vec![]);
+    let pact_json = json!({
+      "consumer": { "name": "uploaded-consumer" },
+      "provider": { "name": "uploaded-provider" },
+      "interactions": [],
+      "metadata": { "pactSpecification": { "version": "4.0" } }
+    });
+    let upload = |id: Option<&str>| HttpRequest {
+      method: "POST".to_string(),
+      query: id.map(|id| hashmap!{ "id".to_string() => vec![Some(id.to_string())] }),
+      body: OptionalBody::Present(pact_json.to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let response = handle_admin_request(&handler.shared, &upload(Some("2")), "/pacts").await;
+    expect!(response.status).to(be_equal_to(201));
+    let response = handle_admin_request(&handler.shared, &upload(None), "/pacts").await;
+    expect!(body_json(&response)["id"].clone()).to(be_equal_to(json!("1")));
+    let response = handle_admin_request(&handler.shared, &upload(None), "/pacts").await;
+    expect!(response.status).to(be_equal_to(201));
+    expect!(body_json(&response)["id"].clone()).to(be_equal_to(json!("3")));
+    expect!(handler.shared.current_sources().len()).to(be_equal_to(3));
+  }
+
+  #[tokio::test]
+  async fn uploaded_pacts_can_be_removed_by_their_encoded_id() {
+    let handler = admin_handler(vec![]);
+    let pact_json = json!({
+      "consumer": { "name": "uploaded-consumer" },
+      "provider": { "name": "uploaded-provider" },
+      "interactions": [],
+      "metadata": { "pactSpecification": { "version": "4.0" } }
+    });
+    let upload = |id: &str| HttpRequest {
+      method: "POST".to_string(),
+      query: Some(hashmap!{ "id".to_string() => vec![Some(id.to_string())] }),
+      body: OptionalBody::Present(pact_json.to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let response = handle_admin_request(&handler.shared, &upload("my pact"), "/pacts").await;
+    expect!(response.status).to(be_equal_to(201));
+    let delete = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &delete, "/pacts/my%20pact").await;
+    expect!(response.status).to(be_equal_to(204));
+    expect!(handler.shared.current_sources().is_empty()).to(be_true());
+
+    let response = handle_admin_request(&handler.shared, &upload("a/b"), "/pacts").await;
+    expect!(response.status).to(be_equal_to(400));
+    let response = handle_admin_request(&handler.shared, &upload(""), "/pacts").await;
+    expect!(response.status).to(be_equal_to(400));
+    expect!(handler.shared.current_sources().is_empty()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn queries_and_clears_the_request_journal() {
+    let interaction = SynchronousHttp {
+      description: "get a user".to_string(),
+      request: HttpRequest { path: "/users/1".to_string(), .. HttpRequest::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact { interactions: vec![ interaction.boxed_v4() ], .. V4Pact::default() };
+    let handler = admin_handler(vec![(pact, PactSource::Unknown)]);
+    let journal = handler.shared.journal.as_ref().unwrap();
+    journal.record(JournalEntry::new(&interaction.request, Some(&interaction), &[], 200));
+    let other = HttpRequest { method: "POST".to_string(), path: "/orders".to_string(), .. HttpRequest::default() };
+    journal.record(JournalEntry::new(&other, None, &[], 404));
+
+    let query = HttpRequest {
+      query: Some(hashmap!{ "path".to_string() => vec![Some("/users/1".to_string())] }),
+      .. HttpRequest::default()
+    };
+    let response = handle_admin_request(&handler.shared, &query, "/requests").await;
+    expect!(response.status).to(be_equal_to(200));
+    let json = body_json(&response);
+    expect!(json["requests"].as_array().unwrap().len()).to(be_equal_to(1));
+    expect!(json["requests"][0]["matched"]["description"].clone()).to(be_equal_to(json!("get a user")));
+    expect!(json["requests"][0]["status"].clone()).to(be_equal_to(json!(200)));
+
+    let query = HttpRequest {
+      query: Some(hashmap!{ "since".to_string() => vec![Some("yesterday".to_string())] }),
+      .. HttpRequest::default()
+    };
+    let response = handle_admin_request(&handler.shared, &query, "/requests").await;
+    expect!(response.status).to(be_equal_to(400));
+
+    let delete = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &delete, "/requests").await;
+    expect!(response.status).to(be_equal_to(204));
+    let response = handle_admin_request(&handler.shared, &HttpRequest::default(), "/requests").await;
+    expect!(body_json(&response)["requests"].as_array().unwrap().is_empty()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn sets_and_resets_the_scenario_state() {
+    let handler = ServerHandler::new(vec![], ServerOptions {
+      admin_prefix: Some("/__pact".to_string()),
+      scenario: Some("start".to_string()),
+      .. ServerOptions::default()
+    });
+    let set = HttpRequest {
+      method: "PUT".to_string(),
+      body: OptionalBody::Present(json!({ "state": "next" }).to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+    let response = handle_admin_request(&handler.shared, &set, "/scenario").await;
+    expect!(body_json(&response)).to(be_equal_to(json!({ "state": "next", "initialState": "start" })));
+
+    let reset = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &reset, "/scenario").await;
+    expect!(body_json(&response)["state"].clone()).to(be_equal_to(json!("start")));
+
+    let response = handle_admin_request(&admin_handler(vec![]).shared, &HttpRequest::default(), "/scenario").await;
+    expect!(response.status).to(be_equal_to(404));
+  }
+
+  #[tokio::test]
+  async fn lists_and_resets_the_interaction_sequences() {
+    let pending = SynchronousHttp {
+      request: HttpRequest { path: "/jobs/1".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 202, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let done = SynchronousHttp {
+      response: HttpResponse { status: 200, .. HttpResponse::default() },
+      .. pending.clone()
+    };
+    let pact = V4Pact { interactions: vec![ pending.boxed_v4(), done.boxed_v4() ], .. V4Pact::default() };
+    let handler = ServerHandler::new(vec![(pact, PactSource::Unknown)], ServerOptions {
+      admin_prefix: Some("/__pact".to_string()),
+      sequence: Some(SequenceMode::Advance),
+      .. ServerOptions::default()
+    });
+    let request = HttpRequest { path: "/jobs/1".to_string(), .. HttpRequest::default() };
+    let mut statuses = vec![];
+    for _ in 0..3 {
+      let Reply::Pact(response) = handle_request(&handler.shared, request.clone(), None, &hashmap!{}).await else {
+        panic!("Expected a response generated from the pact");
+      };
+      statuses.push(response.status);
+    }
+    expect!(statuses).to(be_equal_to(vec![202, 200, 200]));
+
+    let response = handle_admin_request(&handler.shared, &HttpRequest::default(), "/sequences").await;
+    expect!(body_json(&response)).to(be_equal_to(json!({
+      "mode": "advance",
+      "strategy": "first",
+      "positions": { "GET /jobs/1": 3 }
+    })));
+
+    let reset = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &reset, "/sequences").await;
+    expect!(body_json(&response)["positions"].clone()).to(be_equal_to(json!({})));
+    let Reply::Pact(response) = handle_request(&handler.shared, request, None, &hashmap!{}).await else {
+      panic!("Expected a response generated from the pact");
+    };
+    expect!(response.status).to(be_equal_to(202));
+  }
+
+  #[tokio::test]
+  async fn triggers_message_interactions() {
+    let message = AsynchronousMessage {
+      description: "order created".to_string(),
+      contents: MessageContents {
+        contents: OptionalBody::Present(json!({ "id": 1 }).to_string().into(), Some(JSON.clone()), None),
+        .. MessageContents::default()
+      },
+      .. AsynchronousMessage::default()
+    };
+    let pact = V4Pact { interactions: vec![ message.boxed_v4() ], .. V4Pact::default() };
+    let trigger = |body: Value| HttpRequest {
+      method: "POST".to_string(),
+      body: OptionalBody::Present(body.to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let handler = admin_handler(vec![(pact.clone(), PactSource::Unknown)]);
+    let response = handle_admin_request(&handler.shared, &trigger(json!({ "description": "order created" })), "/trigger").await;
+    expect!(response.status).to(be_equal_to(200));
+    expect!(body_json(&response)).to(be_equal_to(json!({ "id": 1 })));
+    let response = handle_admin_request(&handler.shared, &trigger(json!({ "description": "order shipped" })), "/trigger").await;
+    expect!(response.status).to(be_equal_to(404));
+    let response = handle_admin_request(&handler.shared, &trigger(json!({ "id": 1 })), "/trigger").await;
+    expect!(response.status).to(be_equal_to(400));
+
+    let consumer_interaction = SynchronousHttp {
+      request: HttpRequest {
+        method: "POST".to_string(),
+        path: "/events".to_string(),
+        body: OptionalBody::Present(json!({ "id": 1 }).to_string().into(), Some(JSON.clone()), None),
+        .. HttpRequest::default()
+      },
+      response: HttpResponse { status: 202, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let consumer = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ consumer_interaction.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+    let callback_url = Url::parse(&format!("{}/events", consumer.url().unwrap())).unwrap();
+    let handler = ServerHandler::new(vec![(pact, PactSource::Unknown)], ServerOptions {
+      admin_prefix: Some("/__pact".to_string()),
+      message_callback_url: Some(callback_url.clone()),
+      .. ServerOptions::default()
+    });
+    let response = handle_admin_request(&handler.shared, &trigger(json!({ "description": "order created" })), "/trigger").await;
+    expect!(body_json(&response)).to(be_equal_to(json!({
+      "description": "order created",
+      "callbackUrl": callback_url.as_str(),
+      "status": 202
+    })));
+    consumer.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn sets_and_resets_the_provider_state_filter() {
+    let handler = ServerHandler::new(vec![], ServerOptions {
+      admin_prefix: Some("/__pact".to_string()),
+      provider_state: Some(Regex::new("initial").unwrap()),
+      .. ServerOptions::default()
+    });
+    let set = |state: Value| HttpRequest {
+      method: "POST".to_string(),
+      body: OptionalBody::Present(json!({ "state": state }).to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let response = handle_admin_request(&handler.shared, &set(json!("user \\d+")), "/state").await;
+    expect!(response.status).to(be_equal_to(200));
+    expect!(body_json(&response)).to(be_equal_to(json!({ "providerState": "user \\d+", "initialProviderState": "initial" })));
+    expect!(handler.shared.provider_state.lock().unwrap().as_ref().map(|r| r.is_match("user 42"))).to(be_some().value(true));
+
+    let response = handle_admin_request(&handler.shared, &set(json!("[")), "/state").await;
+    expect!(response.status).to(be_equal_to(400));
+
+    let response = handle_admin_request(&handler.shared, &set(Value::Null), "/state").await;
+    expect!(body_json(&response)["providerState"].clone()).to(be_equal_to(Value::Null));
+
+    let reset = HttpRequest { method: "DELETE".to_string(), .. HttpRequest::default() };
+    let response = handle_admin_request(&handler.shared, &reset, "/state").await;
+    expect!(body_json(&response)["providerState"].clone()).to(be_equal_to(json!("initial")));
+  }
+
+  #[tokio::test]
+  async fn provider_state_can_not_be_set_in_scenario_mode() {
+    let handler = ServerHandler::new(vec![], ServerOptions {
+      admin_prefix: Some("/__pact".to_string()),
+      scenario: Some("no orders".to_string()),
+      .. ServerOptions::default()
+    });
+    let set = HttpRequest {
+      method: "POST".to_string(),
+      body: OptionalBody::Present(json!({ "state": "order exists" }).to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let response = handle_admin_request(&handler.shared, &set, "/state").await;
+    expect!(response.status).to(be_equal_to(409));
+    expect!(handler.shared.provider_state.lock().unwrap().is_none()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_pact_uploads() {
+    let upload = HttpRequest {
+      method: "POST".to_string(),
+      body: OptionalBody::Present("not json".into(), None, None),
+      .. HttpRequest::default()
+    };
+    let response = handle_admin_request(&admin_handler(vec![]).shared, &upload, "/pacts").await;
+    expect!(response.status).to(be_equal_to(400));
+  }
+
+  #[tokio::test]
+  async fn uploads_with_plugin_transports_are_accepted_with_a_warning() {
+    let pact_json = json!({
+      "consumer": { "name": "grpc-consumer" },
+      "provider": { "name": "grpc-provider" },
+      "interactions": [
+        {
+          "type": "Synchronous/Messages",
+          "description": "calculate area",
+          "transport": "grpc",
+          "request": { "contents": { "content": "" } },
+          "response": []
+        },
+        {
+          "type": "Synchronous/HTTP",
+          "description": "get area",
+          "request": { "method": "GET", "path": "/area" },
+          "response": { "status": 200 }
+        }
+      ],
+      "metadata": { "pactSpecification": { "version": "4.0" } }
+    });
+    let upload = HttpRequest {
+      method: "POST".to_string(),
+      body: OptionalBody::Present(pact_json.to_string().into(), None, None),
+      .. HttpRequest::default()
+    };
+    let handler = admin_handler(vec![]);
+    let response = handle_admin_request(&handler.shared, &upload, "/pacts").await;
+    expect!(response.status).to(be_equal_to(201));
+    let json = body_json(&response);
+    expect!(json["interactions"].clone()).to(be_equal_to(json!(2)));
+    expect!(json["warning"].as_str().unwrap().contains("grpc")).to(be_true());
+    expect!(handler.shared.current_sources().len()).to(be_equal_to(1));
+  }
+}
@@ -0,0 +1,575 @@
+//! Library API for running a stub server in-process
+
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use itertools::Itertools;
+use pact_models::prelude::v4::V4Pact;
+use regex::Regex;
+use reqwest::Url;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::listener::{BindAddress, LocalAddress};
+use crate::loading::load_pacts;
+use crate::{plugins, PactSource};
+use crate::recorder::RecordOptions;
+use crate::server::{self, ListenOptions, ServerHandler, ServerOptions};
+use crate::strategy::{MatchStrategy, SequenceMode};
+use crate::tls::TlsConfig;
+
+/// Builder for starting a stub server from within a Rust program.
+///
+/// ```no_run
+/// # async fn example(pact: pact_models::prelude::v4::V4Pact) -> anyhow::Result<()> {
+/// use pact_stub_server::StubServerBuilder;
+///
+/// let server = StubServerBuilder::new()
+///   .with_pact(pact)
+///   .host("127.0.0.1".parse().unwrap())
+///   .start()
+///   .await?;
+/// let port = server.port().unwrap();
+/// // ... make requests against the stub ...
+/// server.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StubServerBuilder {
+  pacts: Vec<(V4Pact, PactSource)>,
+  sources: Vec<PactSource>,
+  insecure_tls: bool,
+  extension: Option<String>,
+  listen_options: ListenOptions,
+  server_options: ServerOptions
+}
+
+impl StubServerBuilder {
+  /// Creates a builder that binds to a random port on all interfaces
+  pub fn new() -> Self {
+    StubServerBuilder::default()
+  }
+
+  /// Serve the interactions from an already loaded pact
+  pub fn with_pact(mut self, pact: V4Pact) -> Self {
+    self.pacts.push((pact, PactSource::Embedded));
+    self
+  }
+
+  /// Load pacts from the source when the server is started
+  pub fn with_source(mut self, source: PactSource) -> Self {
+    self.sources.push(source);
+    self
+  }
+
+  /// Disables TLS certificate validation when fetching pacts from URLs or a Pact Broker
+  pub fn insecure_tls(mut self, insecure_tls: bool) -> Self {
+    self.insecure_tls = insecure_tls;
+    self
+  }
+
+  /// File extension to use when loading pacts from a directory source (default is json)
+  pub fn extension<S: Into<String>>(mut self, extension: S) -> Self {
+    self.extension = Some(extension.into());
+    self
+  }
+
+  /// Address to bind to
+  pub fn host(mut self, host: BindAddress) -> Self {
+    self.listen_options.host = host;
+    self
+  }
+
+  /// Port to bind to. Defaults to a random port assigned by the OS.
+  pub fn port(mut self, port: u16) -> Self {
+    self.listen_options.port = port;
+    self
+  }
+
+  /// Serve HTTPS using the given certificate configuration
+  pub fn tls(mut self, tls: TlsConfig) -> Self {
+    self.listen_options.tls = Some(tls);
+    self
+  }
+
+  /// How long to wait for in-flight requests to complete when shutting down
+  pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+    self.listen_options.shutdown_timeout = timeout;
+    self
+  }
+
+  /// Automatically respond to OPTIONS requests and return default CORS headers
+  pub fn cors(mut self, auto_cors: bool) -> Self {
+    self.server_options.auto_cors = auto_cors;
+    self
+  }
+
+  /// Set the CORS Access-Control-Allow-Origin header to the Referer
+  pub fn cors_referer(mut self, cors_referer: bool) -> Self {
+    self.server_options.cors_referer = cors_referer;
+    self
+  }
+
+  /// Provider state regular expression to filter the responses by
+  pub fn provider_state(mut self, provider_state: Regex) -> Self {
+    self.server_options.provider_state = Some(provider_state);
+    self
+  }
+
+  /// Name of the header containing the provider state to use for a request
+  pub fn provider_state_header_name<S: Into<String>>(mut self, name: S) -> Self {
+    self.server_options.provider_state_header_name = Some(name.into());
+    self
+  }
+
+  /// Match the provider state header value exactly, instead of as a regular expression
+  pub fn provider_state_header_literal(mut self, literal: bool) -> Self {
+    self.server_options.provider_state_header_literal = literal;
+    self
+  }
+
+  /// Provider state parameter to filter the responses by
+  pub fn provider_state_param<S: Into<String>>(mut self, name: S, value: serde_json::Value) -> Self {
+    self.server_options.provider_state_params.insert(name.into(), value);
+    self
+  }
+
+  /// Name of the header containing a JSON object of provider state parameters to use for a request
+  pub fn provider_state_params_header_name<S: Into<String>>(mut self, name: S) -> Self {
+    self.server_options.provider_state_params_header_name = Some(name.into());
+    self
+  }
+
+  /// Include empty provider states when filtering with a provider state
+  pub fn empty_provider_states(mut self, empty_provider_states: bool) -> Self {
+    self.server_options.empty_provider_states = empty_provider_states;
+    self
+  }
+
+  /// Return a JSON body describing the closest interactions when no interaction matches a request
+  pub fn explain_mismatches(mut self, explain_mismatches: bool) -> Self {
+    self.server_options.explain_mismatches = explain_mismatches;
+    self
+  }
+
+  /// How to select the interaction when more than one matches a request (defaults to the one
+  /// with the fewest mismatches)
+  pub fn match_strategy(mut self, strategy: MatchStrategy) -> Self {
+    self.server_options.match_strategy = strategy;
+    self
+  }
+
+  /// Use the matching interactions in sequence for repeated requests with the same method, path
+  /// and query, in the order they were loaded
+  pub fn sequence(mut self, mode: SequenceMode) -> Self {
+    self.server_options.sequence = Some(mode);
+    self
+  }
+
+  /// Only use interactions whose expected headers all match the request
+  pub fn strict_headers(mut self, strict_headers: bool) -> Self {
+    self.server_options.strict_headers = strict_headers;
+    self
+  }
+
+  /// Only use interactions whose expected value for this header matches the request
+  pub fn strict_header<S: Into<String>>(mut self, name: S) -> Self {
+    self.server_options.strict_header_names.push(name.into());
+    self
+  }
+
+  /// Methods whose request bodies must match the interaction, or `*` for all methods (defaults
+  /// to POST, PUT, PATCH and QUERY)
+  pub fn body_methods<S: Into<String>>(mut self, methods: Vec<S>) -> Self {
+    self.server_options.body_methods = Some(methods.into_iter().map(|method| method.into()).collect());
+    self
+  }
+
+  /// Status code to return when no interaction matches a request (default is 404)
+  pub fn unmatched_status(mut self, status: u16) -> Self {
+    self.server_options.unmatched_response.status = status;
+    self
+  }
+
+  /// Header to add to the response when no interaction matches a request
+  pub fn unmatched_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+    self.server_options.unmatched_response.headers.push((name.into(), value.into()));
+    self
+  }
+
+  /// Body to return when no interaction matches a request
+  pub fn unmatched_body<B: Into<Bytes>>(mut self, body: B) -> Self {
+    self.server_options.unmatched_response.body = Some(body.into());
+    self
+  }
+
+  /// Forward requests that do not match any interaction to this URL
+  pub fn proxy_url(mut self, url: Url) -> Self {
+    self.server_options.proxy_url = Some(url);
+    self
+  }
+
+  /// Forward requests that do not match any interaction to a provider and record them in a
+  /// pact file
+  pub fn record(mut self, options: RecordOptions) -> Self {
+    self.server_options.record = Some(options);
+    self
+  }
+
+  /// Select interactions by the current state of a scenario, starting in the given provider
+  /// state. Matched interactions move the scenario to the state in their `nextState` comment.
+  pub fn scenario<S: Into<String>>(mut self, initial_state: S) -> Self {
+    self.server_options.scenario = Some(initial_state.into());
+    self
+  }
+
+  /// Enable the admin API under the given path prefix (for example `/__pact`)
+  pub fn admin_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+    self.server_options.admin_prefix = Some(format!("/{}", prefix.into().trim_matches('/')));
+    self
+  }
+
+  /// Maximum number of requests kept in the admin API request journal (default is 1000)
+  pub fn journal_size(mut self, size: usize) -> Self {
+    self.server_options.journal_size = Some(size);
+    self
+  }
+
+  /// URL to POST messages triggered through the admin API to, instead of returning them
+  pub fn message_callback_url(mut self, url: Url) -> Self {
+    self.server_options.message_callback_url = Some(url);
+    self
+  }
+
+  /// Accept WebSocket connections on the path, and push the asynchronous messages triggered
+  /// through the admin API to them
+  pub fn websocket_path<S: Into<String>>(mut self, path: S) -> Self {
+    self.server_options.websocket_path = Some(path.into());
+    self
+  }
+
+  /// Push the next asynchronous message to the WebSocket clients at this interval
+  pub fn websocket_interval(mut self, interval: Duration) -> Self {
+    self.server_options.websocket_interval = Some(interval);
+    self
+  }
+
+  /// Delay between the events of Server-Sent Events responses (default is 1 second)
+  pub fn event_delay(mut self, delay: Duration) -> Self {
+    self.server_options.event_delay = Some(delay);
+    self
+  }
+
+  /// Loads any pact sources, binds the listener and starts serving requests on the current
+  /// Tokio runtime. Returns once the server is accepting connections.
+  pub async fn start(self) -> anyhow::Result<StubServerHandle> {
+    let mut pacts = self.pacts;
+    if !self.sources.is_empty() {
+      let loaded = load_pacts(self.sources, self.insecure_tls, self.extension.as_ref()).await;
+      let errors = loaded.iter()
+        .filter_map(|result| result.as_ref().err())
+        .map(|err| err.to_string())
+        .collect_vec();
+      if !errors.is_empty() {
+        return Err(anyhow!("There were errors loading the pact files: {}", errors.join(", ")));
+      }
+      pacts.extend(loaded.iter()
+        .filter_map(|result| result.as_ref().ok())
+        .map(|(p, s)| (p.as_v4_pact().unwrap(), s.clone())));
+    }
+    for warning in pacts.iter().filter_map(|(pact, _)| plugins::plugin_transport_warning(pact)) {
+      warn!("{}", warning);
+    }
+    let interactions: usize = pacts.iter().map(|(p, _)| p.interactions.len()).sum();
+    info!("Loaded {} pacts ({} total interactions)", pacts.len(), interactions);
+
+    let handler = ServerHandler::new(pacts, self.server_options);
+    let https = self.listen_options.tls.is_some();
+    let listener = server::bind(self.listen_options).await?;
+    let address = listener.local_address().clone();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(handler.serve(listener, async move {
+      let _ = shutdown_rx.await;
+    }));
+
+    Ok(StubServerHandle { address, https, shutdown_tx, task })
+  }
+}
+
+/// Handle to a running stub server. Dropping the handle will also shut the server down.
+#[derive(Debug)]
+pub struct StubServerHandle {
+  address: LocalAddress,
+  https: bool,
+  shutdown_tx: oneshot::Sender<()>,
+  task: JoinHandle<Result<(), ExitCode>>
+}
+
+impl StubServerHandle {
+  /// Address the server is listening on
+  pub fn address(&self) -> &LocalAddress {
+    &self.address
+  }
+
+  /// Socket address the server is listening on, if bound to TCP
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.address.socket_addr()
+  }
+
+  /// Port the server is listening on, if bound to TCP
+  pub fn port(&self) -> Option<u16> {
+    self.address.port()
+  }
+
+  /// Base URL for making requests to the server, if bound to TCP. Unspecified addresses are
+  /// replaced with the loopback address.
+  pub fn url(&self) -> Option<String> {
+    self.local_addr().map(|mut addr| {
+      if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+          IpAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+          IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)
+        });
+      }
+      format!("{}://{}", if self.https { "https" } else { "http" }, addr)
+    })
+  }
+
+  /// Stops accepting connections and waits for in-flight requests to complete
+  pub async fn shutdown(self) -> anyhow::Result<()> {
+    let _ = self.shutdown_tx.send(());
+    match self.task.await {
+      Ok(Ok(())) => Ok(()),
+      Ok(Err(code)) => Err(anyhow!("Stub server failed with {:?}", code)),
+      Err(err) => Err(anyhow!("Stub server task failed: {}", err))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::bodies::OptionalBody;
+  use pact_models::pact::read_pact;
+  use pact_models::v4::interaction::V4Interaction;
+  use pact_models::prelude::v4::*;
+  use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+  use pact_models::v4::sync_message::SynchronousMessage;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn starts_a_server_on_a_random_port_and_shuts_it_down() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/test".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 204, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact { interactions: vec![ interaction.boxed_v4() ], .. V4Pact::default() };
+
+    let server = StubServerBuilder::new()
+      .with_pact(pact)
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+    expect!(server.port()).to_not(be_some().value(0));
+
+    let response = reqwest::get(format!("{}/test", server.url().unwrap())).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(204));
+
+    expect!(server.shutdown().await.is_ok()).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn proxies_unmatched_requests_to_the_proxy_url() {
+    let upstream_interaction = SynchronousHttp {
+      request: HttpRequest { method: "POST".to_string(), path: "/upstream".to_string(), .. HttpRequest::default() },
+      response: HttpResponse {
+        status: 202,
+        body: OptionalBody::Present("from upstream".into(), None, None),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let upstream = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ upstream_interaction.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+    let server = StubServerBuilder::new()
+      .host("127.0.0.1".parse().unwrap())
+      .proxy_url(Url::parse(upstream.url().unwrap().as_str()).unwrap())
+      .start()
+      .await
+      .unwrap();
+
+    let response = reqwest::Client::new()
+      .post(format!("{}/upstream", server.url().unwrap()))
+      .send()
+      .await
+      .unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(202));
+    expect!(response.text().await.unwrap()).to(be_equal_to("from upstream"));
+
+    let response = reqwest::get(format!("{}/missing", server.url().unwrap())).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(404));
+
+    server.shutdown().await.unwrap();
+    upstream.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn serves_recorded_interactions_without_forwarding_them_again() {
+    let upstream_interaction = SynchronousHttp {
+      request: HttpRequest { path: "/users".to_string(), .. HttpRequest::default() },
+      response: HttpResponse {
+        status: 200,
+        body: OptionalBody::Present("recorded".into(), None, None),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let upstream = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ upstream_interaction.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+    let pact_file = std::env::temp_dir().join(format!("pact-stub-server-replay-{}.json", std::process::id()));
+    let server = StubServerBuilder::new()
+      .host("127.0.0.1".parse().unwrap())
+      .record(RecordOptions {
+        pact_file: pact_file.clone(),
+        upstream: Url::parse(upstream.url().unwrap().as_str()).unwrap(),
+        consumer: "consumer".to_string(),
+        provider: "provider".to_string()
+      })
+      .start()
+      .await
+      .unwrap();
+
+    let url = format!("{}/users", server.url().unwrap());
+    expect!(reqwest::get(&url).await.unwrap().text().await.unwrap()).to(be_equal_to("recorded"));
+    upstream.shutdown().await.unwrap();
+    let response = reqwest::get(&url).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(200));
+    expect!(response.text().await.unwrap()).to(be_equal_to("recorded"));
+    server.shutdown().await.unwrap();
+
+    let descriptions = read_pact(&pact_file).unwrap().interactions().iter()
+      .map(|interaction| interaction.description())
+      .collect::<Vec<_>>();
+    expect!(descriptions).to(be_equal_to(vec!["GET /users".to_string()]));
+    std::fs::remove_file(&pact_file).unwrap();
+  }
+
+  #[tokio::test]
+  async fn matches_the_body_of_query_requests() {
+    let interaction = |body: &str, status: u16| SynchronousHttp {
+      request: HttpRequest {
+        method: "QUERY".to_string(),
+        path: "/search".to_string(),
+        body: OptionalBody::Present(body.to_string().into(), None, None),
+        .. HttpRequest::default()
+      },
+      response: HttpResponse { status, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction("name=alice", 200).boxed_v4(), interaction("name=bob", 201).boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let server = StubServerBuilder::new()
+      .with_pact(pact)
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+
+    let client = reqwest::Client::new();
+    let query = |body: &'static str| client
+      .request(reqwest::Method::from_bytes(b"QUERY").unwrap(), format!("{}/search", server.url().unwrap()))
+      .body(body)
+      .send();
+    expect!(query("name=bob").await.unwrap().status().as_u16()).to(be_equal_to(201));
+    expect!(query("name=alice").await.unwrap().status().as_u16()).to(be_equal_to(200));
+    expect!(query("name=carol").await.unwrap().status().as_u16()).to(be_equal_to(404));
+
+    server.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn journals_requests_rejected_for_invalid_provider_state_headers() {
+    let server = StubServerBuilder::new()
+      .host("127.0.0.1".parse().unwrap())
+      .provider_state_header_name("X-Provider-State")
+      .admin_prefix("/__pact")
+      .start()
+      .await
+      .unwrap();
+
+    let client = reqwest::Client::new();
+    let response = client.get(format!("{}/users", server.url().unwrap()))
+      .header("X-Provider-State", "[")
+      .send()
+      .await
+      .unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(400));
+
+    let json: serde_json::Value = client.get(format!("{}/__pact/requests", server.url().unwrap()))
+      .send()
+      .await
+      .unwrap()
+      .json()
+      .await
+      .unwrap();
+    let entry = &json["requests"][0];
+    expect!(entry["request"]["path"].as_str()).to(be_some().value("/users"));
+    expect!(entry["status"].as_u64()).to(be_some().value(400));
+    expect!(entry["matched"].is_null()).to(be_true());
+    expect!(entry["error"].as_str().unwrap_or_default().contains("X-Provider-State")).to(be_true());
+
+    server.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn serves_the_http_interactions_of_pacts_with_plugin_transports() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/area".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 204, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let grpc_interaction = SynchronousMessage {
+      description: "calculate area".to_string(),
+      transport: Some("grpc".to_string()),
+      .. SynchronousMessage::default()
+    };
+    let server = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ interaction.boxed_v4(), grpc_interaction.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .start()
+      .await
+      .unwrap();
+
+    let response = reqwest::get(format!("{}/area", server.url().unwrap())).await.unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(204));
+
+    server.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn fails_to_start_if_a_source_can_not_be_loaded() {
+    let result = StubServerBuilder::new()
+      .with_source(PactSource::File("/does/not/exist.json".to_string()))
+      .start()
+      .await;
+    expect!(result.is_err()).to(be_true());
+  }
+}
@@ -0,0 +1,180 @@
+//! Server-Sent Events responses. The body of an event stream interaction is split into its
+//! events, which are sent with a delay between them, and the connection is then kept open like a
+//! real SSE endpoint until the client disconnects or the server shuts down.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use futures::{stream, StreamExt};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::Response;
+use pact_models::bodies::OptionalBody;
+use pact_models::http_parts::HttpPart;
+use pact_models::prelude::v4::SynchronousHttp;
+use pact_models::v4::http_parts::HttpResponse;
+use serde_json::Value;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::pact_support;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, Infallible>;
+
+/// Content type of Server-Sent Events responses
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+/// Delay between events when it is not configured
+pub const DEFAULT_EVENT_DELAY: Duration = Duration::from_millis(1000);
+
+/// Interaction comment that marks the response as an event stream. The value is `true`, `false`
+/// or an object with the `delay` in milliseconds between the events.
+pub const EVENT_STREAM_COMMENT: &str = "eventStream";
+
+/// Delay between the events if the response to the interaction should be streamed as Server-Sent
+/// Events. Responses are streamed if the interaction has the `eventStream` comment, or the
+/// response has the `text/event-stream` content type.
+pub fn stream_delay(interaction: &SynchronousHttp, response: &HttpResponse, default_delay: Duration) -> Option<Duration> {
+  match interaction.comments.get(EVENT_STREAM_COMMENT) {
+    Some(Value::Bool(false)) => None,
+    Some(Value::Object(config)) => Some(config.get("delay")
+      .and_then(|delay| delay.as_u64())
+      .map(Duration::from_millis)
+      .unwrap_or(default_delay)),
+    Some(_) => Some(default_delay),
+    None => response.content_type()
+      .filter(|content_type| content_type.base_type() == EVENT_STREAM_CONTENT_TYPE)
+      .map(|_| default_delay)
+  }
+}
+
+/// Splits the body into its events, which are separated by blank lines. Each event ends with a
+/// blank line, so it can be sent on its own.
+pub fn split_events(body: &[u8]) -> Vec<Bytes> {
+  let body = String::from_utf8_lossy(body).replace("\r\n", "\n").replace('\r', "\n");
+  body.split("\n\n")
+    .filter(|event| !event.trim().is_empty())
+    .map(|event| Bytes::from(format!("{}\n\n", event.trim_matches('\n'))))
+    .collect()
+}
+
+/// Streams the events in the response body, waiting for the delay between them. The stream
+/// stays open once the events are sent, and ends when `stopping` is set.
+pub fn event_stream_response(
+  response: &HttpResponse,
+  delay: Duration,
+  mut stopping: watch::Receiver<bool>
+) -> Result<Response<BoxBody>, http::Error> {
+  let events = response.body.value().map(|body| split_events(&body)).unwrap_or_default();
+  info!("Streaming {} event(s) with a delay of {:?}", events.len(), delay);
+
+  let mut headers = HttpResponse {
+    body: OptionalBody::Missing,
+    .. response.clone()
+  };
+  if let Some(headers) = headers.headers.as_mut() {
+    headers.retain(|name, _| !name.eq_ignore_ascii_case("content-length"));
+  }
+  if !headers.has_header("Content-Type") {
+    headers.add_header("Content-Type", vec![EVENT_STREAM_CONTENT_TYPE]);
+  }
+  if !headers.has_header("Cache-Control") {
+    headers.add_header("Cache-Control", vec!["no-cache"]);
+  }
+
+  let events = stream::iter(events.into_iter().enumerate())
+    .then(move |(index, event)| async move {
+      if index > 0 {
+        tokio::time::sleep(delay).await;
+      }
+      Ok::<_, Infallible>(Frame::data(event))
+    });
+  let keep_open = stream::once(async move {
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+  }).filter_map(|_| async { None });
+  pact_support::pact_response_to_hyper_response(&headers)
+    .map(|response| response.map(|_| BodyExt::boxed(StreamBody::new(events.chain(keep_open)))))
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::bodies::OptionalBody;
+  use pact_models::content_types::JSON;
+  use pact_models::prelude::v4::V4Pact;
+  use pact_models::v4::http_parts::HttpRequest;
+  use pact_models::v4::interaction::V4Interaction;
+  use serde_json::json;
+
+  use crate::builder::StubServerBuilder;
+
+  use super::*;
+
+  #[test]
+  fn splits_the_body_into_events() {
+    let events = split_events(b"event: created\ndata: {\"id\": 1}\n\ndata: two\r\n\r\n\n\n: comment\n");
+    expect!(events).to(be_equal_to(vec![
+      Bytes::from("event: created\ndata: {\"id\": 1}\n\n"),
+      Bytes::from("data: two\n\n"),
+      Bytes::from(": comment\n\n")
+    ]));
+    expect!(split_events(b"")).to(be_equal_to(Vec::<Bytes>::new()));
+  }
+
+  #[test]
+  fn streams_event_stream_responses_and_flagged_interactions() {
+    let response = |content_type: &str| HttpResponse {
+      headers: Some(hashmap!{ "Content-Type".to_string() => vec![content_type.to_string()] }),
+      body: OptionalBody::Present("data: 1\n\n".into(), None, None),
+      .. HttpResponse::default()
+    };
+    let interaction = |comment: Option<Value>| SynchronousHttp {
+      comments: comment.map(|comment| hashmap!{ EVENT_STREAM_COMMENT.to_string() => comment }).unwrap_or_default(),
+      .. SynchronousHttp::default()
+    };
+    let default_delay = Duration::from_millis(100);
+
+    expect!(stream_delay(&interaction(None), &response("text/event-stream"), default_delay))
+      .to(be_some().value(default_delay));
+    expect!(stream_delay(&interaction(None), &response("text/event-stream; charset=utf-8"), default_delay))
+      .to(be_some().value(default_delay));
+    expect!(stream_delay(&interaction(None), &response(&JSON.to_string()), default_delay)).to(be_none());
+    expect!(stream_delay(&interaction(Some(json!(true))), &response("text/plain"), default_delay))
+      .to(be_some().value(default_delay));
+    expect!(stream_delay(&interaction(Some(json!({ "delay": 250 }))), &response("text/plain"), default_delay))
+      .to(be_some().value(Duration::from_millis(250)));
+    expect!(stream_delay(&interaction(Some(json!(false))), &response("text/event-stream"), default_delay))
+      .to(be_none());
+  }
+
+  #[tokio::test]
+  async fn streams_the_events_and_keeps_the_connection_open() {
+    let interaction = SynchronousHttp {
+      request: HttpRequest { path: "/events".to_string(), .. HttpRequest::default() },
+      response: HttpResponse {
+        headers: Some(hashmap!{ "Content-Type".to_string() => vec![EVENT_STREAM_CONTENT_TYPE.to_string()] }),
+        body: OptionalBody::Present("data: one\n\ndata: two\n\n".into(), None, None),
+        .. HttpResponse::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let server = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ interaction.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .event_delay(Duration::from_millis(50))
+      .start()
+      .await
+      .unwrap();
+
+    let mut response = reqwest::get(format!("{}/events", server.url().unwrap())).await.unwrap();
+    expect!(response.headers().get("content-type").unwrap().to_str().unwrap()).to(be_equal_to(EVENT_STREAM_CONTENT_TYPE));
+    expect!(response.chunk().await.unwrap()).to(be_some().value(Bytes::from("data: one\n\n")));
+    expect!(response.chunk().await.unwrap()).to(be_some().value(Bytes::from("data: two\n\n")));
+    let next = tokio::time::timeout(Duration::from_millis(200), response.chunk()).await;
+    expect!(next.is_err()).to(be_true());
+
+    server.shutdown().await.unwrap();
+    expect!(response.chunk().await.unwrap()).to(be_none());
+  }
+}
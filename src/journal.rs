@@ -0,0 +1,188 @@
+//! In-memory journal of the requests received by the stub server
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use pact_matching::Mismatch;
+use pact_models::prelude::v4::SynchronousHttp;
+use pact_models::v4::http_parts::HttpRequest;
+use pact_models::v4::interaction::V4Interaction;
+use serde_json::{json, Value};
+
+/// Default number of requests kept in the journal
+pub const DEFAULT_JOURNAL_SIZE: usize = 1000;
+
+/// A request received by the server, with the outcome of matching it against the interactions
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+  /// When the request was received
+  pub timestamp: DateTime<Utc>,
+  /// The request received
+  pub request: HttpRequest,
+  /// Key and description of the interaction used to generate the response
+  pub interaction: Option<(String, String)>,
+  /// Description of each candidate interaction considered, with its mismatches
+  pub mismatches: Vec<(String, Vec<Mismatch>)>,
+  /// Status of the response returned
+  pub status: u16,
+  /// Why the request was rejected before it could be matched
+  pub error: Option<String>
+}
+
+impl JournalEntry {
+  /// Creates an entry for a request received now
+  pub fn new(
+    request: &HttpRequest,
+    interaction: Option<&SynchronousHttp>,
+    candidates: &[(SynchronousHttp, Vec<Mismatch>)],
+    status: u16
+  ) -> Self {
+    JournalEntry {
+      timestamp: Utc::now(),
+      request: request.clone(),
+      interaction: interaction.map(|i| (i.unique_key(), i.description.clone())),
+      mismatches: candidates.iter()
+        .map(|(i, mismatches)| (i.description.clone(), mismatches.clone()))
+        .collect(),
+      status,
+      error: None
+    }
+  }
+
+  /// Creates an entry for a request that was rejected before it could be matched
+  pub fn rejected(request: &HttpRequest, status: u16, error: &str) -> Self {
+    JournalEntry {
+      error: Some(error.to_string()),
+      .. JournalEntry::new(request, None, &[], status)
+    }
+  }
+
+  /// Converts the entry to a JSON value
+  pub fn to_json(&self) -> Value {
+    let mut json = json!({
+      "timestamp": self.timestamp.to_rfc3339(),
+      "request": self.request.to_json(),
+      "matched": self.interaction.as_ref().map(|(key, description)| json!({
+        "key": key,
+        "description": description
+      })),
+      "candidates": self.mismatches.iter().map(|(description, mismatches)| json!({
+        "description": description,
+        "mismatches": mismatches.iter().map(|m| m.to_json()).collect::<Vec<_>>()
+      })).collect::<Vec<_>>(),
+      "status": self.status
+    });
+    if let Some(error) = &self.error {
+      json["error"] = json!(error);
+    }
+    json
+  }
+}
+
+/// Criteria for selecting entries from the journal
+#[derive(Debug, Clone, Default)]
+pub struct JournalFilter {
+  /// Only entries for this request path
+  pub path: Option<String>,
+  /// Only entries for this request method (case-insensitive)
+  pub method: Option<String>,
+  /// Only entries received at or after this time
+  pub since: Option<DateTime<Utc>>,
+  /// Only entries received at or before this time
+  pub until: Option<DateTime<Utc>>
+}
+
+impl JournalFilter {
+  fn matches(&self, entry: &JournalEntry) -> bool {
+    self.path.as_ref().map(|path| *path == entry.request.path).unwrap_or(true) &&
+      self.method.as_ref().map(|method| method.eq_ignore_ascii_case(&entry.request.method)).unwrap_or(true) &&
+      self.since.map(|since| entry.timestamp >= since).unwrap_or(true) &&
+      self.until.map(|until| entry.timestamp <= until).unwrap_or(true)
+  }
+}
+
+/// Bounded journal of requests. Once full, the oldest entries are discarded.
+#[derive(Debug)]
+pub struct Journal {
+  entries: Mutex<VecDeque<JournalEntry>>,
+  capacity: usize
+}
+
+impl Journal {
+  /// Creates a journal that keeps at most `capacity` entries
+  pub fn new(capacity: usize) -> Self {
+    Journal {
+      entries: Mutex::new(VecDeque::with_capacity(capacity.min(DEFAULT_JOURNAL_SIZE))),
+      capacity
+    }
+  }
+
+  /// Adds an entry, discarding the oldest one if the journal is full
+  pub fn record(&self, entry: JournalEntry) {
+    if self.capacity == 0 {
+      return;
+    }
+    let mut entries = self.entries.lock().unwrap();
+    while entries.len() >= self.capacity {
+      entries.pop_front();
+    }
+    entries.push_back(entry);
+  }
+
+  /// Entries matching the filter, oldest first
+  pub fn entries(&self, filter: &JournalFilter) -> Vec<JournalEntry> {
+    self.entries.lock().unwrap().iter()
+      .filter(|entry| filter.matches(entry))
+      .cloned()
+      .collect()
+  }
+
+  /// Removes all entries
+  pub fn clear(&self) {
+    self.entries.lock().unwrap().clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+
+  use super::*;
+
+  fn entry(method: &str, path: &str) -> JournalEntry {
+    let request = HttpRequest { method: method.to_string(), path: path.to_string(), .. HttpRequest::default() };
+    JournalEntry::new(&request, None, &[], 404)
+  }
+
+  #[test]
+  fn discards_the_oldest_entries_once_full() {
+    let journal = Journal::new(2);
+    journal.record(entry("GET", "/one"));
+    journal.record(entry("GET", "/two"));
+    journal.record(entry("GET", "/three"));
+
+    let paths = journal.entries(&JournalFilter::default()).iter()
+      .map(|e| e.request.path.clone())
+      .collect::<Vec<_>>();
+    expect!(paths).to(be_equal_to(vec!["/two".to_string(), "/three".to_string()]));
+  }
+
+  #[test]
+  fn filters_entries_by_path_method_and_time() {
+    let journal = Journal::new(10);
+    journal.record(entry("GET", "/users"));
+    journal.record(entry("POST", "/users"));
+    journal.record(entry("GET", "/orders"));
+
+    let filter = JournalFilter { path: Some("/users".to_string()), .. JournalFilter::default() };
+    expect!(journal.entries(&filter).len()).to(be_equal_to(2));
+    let filter = JournalFilter { path: Some("/users".to_string()), method: Some("post".to_string()), .. JournalFilter::default() };
+    expect!(journal.entries(&filter).len()).to(be_equal_to(1));
+    let filter = JournalFilter { since: Some(Utc::now() + chrono::Duration::seconds(60)), .. JournalFilter::default() };
+    expect!(journal.entries(&filter).is_empty()).to(be_true());
+
+    journal.clear();
+    expect!(journal.entries(&JournalFilter::default()).is_empty()).to(be_true());
+  }
+}
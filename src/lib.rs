@@ -34,6 +34,20 @@
 //!           Bearer token to use when fetching pacts from URLS or Pact Broker
 //!   -p, --port <port>
 //!           Port to run on (defaults to random port assigned by the OS)
+//!       --host <host>
+//!           Address to bind to. Accepts an IPv4 or IPv6 address, or unix:<path> for a Unix domain socket [default: 0.0.0.0]
+//!       --tls-cert <tls-cert>
+//!           PEM file containing the certificate chain to serve HTTPS with
+//!       --tls-key <tls-key>
+//!           PEM file containing the private key for the certificate given with --tls-cert
+//!       --self-signed
+//!           Serve HTTPS using a self-signed certificate generated at startup
+//!       --shutdown-timeout <shutdown-timeout>
+//!           Seconds to wait for in-flight requests to complete when shutting down [default: 10]
+//!       --port-file <port-file>
+//!           File to write the bound port to once the server is listening
+//!       --print-port
+//!           Print the bound port and host to stdout as a JSON line once the server is listening, and log to stderr
 //!   -o, --cors
 //!           Automatically respond to OPTIONS requests and return default CORS headers
 //!       --cors-referer
@@ -44,12 +58,62 @@
 //!           Provider state regular expression to filter the responses by
 //!       --provider-state-header-name <provider-state-header-name>
 //!           Name of the header parameter containing the provider state to be used in case multiple matching interactions are found
+//!       --provider-state-header-literal
+//!           Match the provider state header value exactly, instead of as a regular expression
+//!       --provider-state-param <provider-state-param>
+//!           Provider state parameter to filter the responses by, in the form name=value (can be repeated). Values are parsed as JSON if possible
+//!       --provider-state-params-header-name <provider-state-params-header-name>
+//!           Name of the header containing a JSON object of provider state parameters to filter the responses by
 //!       --empty-provider-state
 //!           Include empty provider states when filtering with --provider-state
+//!       --explain-mismatches
+//!           Return a JSON body describing the closest interactions and their mismatches when no interaction matches a request
+//!       --match-strategy <match-strategy>
+//!           How to select the interaction when more than one matches a request [default: fewest-mismatches] [possible values: first, fewest-mismatches, most-specific, round-robin, error]
+//!       --sequence <sequence>
+//!           Use the matching interactions in the order they were loaded for repeated requests with the same method, path and query, either cycling back to the first or staying on the last [possible values: cycle, advance]
+//!       --strict-headers
+//!           Only use interactions whose expected headers all match the request
+//!       --strict-header <strict-header>
+//!           Only use interactions whose expected value for this header matches the request (can be repeated)
+//!       --body-methods <body-methods>
+//!           Comma-separated methods whose request bodies must match the interaction, or * for all methods [default: POST,PUT,PATCH,QUERY]
+//!       --unmatched-status <unmatched-status>
+//!           Status code to return when no interaction matches a request [default: 404]
+//!       --unmatched-body-file <unmatched-body-file>
+//!           File containing the body to return when no interaction matches a request
+//!       --unmatched-header <unmatched-header>
+//!           Header to add to the response when no interaction matches a request, in the form 'Name: value' (can be repeated)
+//!       --proxy-url <proxy-url>
+//!           URL to forward requests to when no interaction matches them
+//!       --record <record>
+//!           Pact file to record requests that no interaction matches into, after forwarding them to --record-upstream
+//!       --record-upstream <record-upstream>
+//!           URL of the provider to forward requests to when recording
+//!       --record-consumer <record-consumer>
+//!           Consumer name to use for the recorded pact [default: consumer]
+//!       --record-provider <record-provider>
+//!           Provider name to use for the recorded pact [default: provider]
+//!       --scenario <scenario>
+//!           Select interactions by the current state of a scenario, starting in this provider state. Matched interactions move the scenario to the state given by their 'nextState' comment
 //!      --consumer-name <consumer-name>
 //!           Consumer name to use to filter the Pacts fetched from the Pact broker (can be repeated)
 //!       --provider-name <provider-name>
 //!           Provider name to use to filter the Pacts fetched from the Pact broker (can be repeated)
+//!       --admin
+//!           Enable the admin API for inspecting and controlling the running server
+//!       --admin-prefix <admin-prefix>
+//!           Path prefix the admin API is served under [default: /__pact]
+//!       --journal-size <journal-size>
+//!           Maximum number of requests to keep in the admin API request journal [default: 1000]
+//!       --message-callback-url <message-callback-url>
+//!           URL to POST messages triggered through the admin API to, instead of returning them
+//!       --websocket-path <websocket-path>
+//!           Path to accept WebSocket connections on, which asynchronous messages are pushed to
+//!       --websocket-interval <websocket-interval>
+//!           Milliseconds between pushing the next asynchronous message to the WebSocket clients
+//!       --event-delay <event-delay>
+//!           Milliseconds between the events of Server-Sent Events responses [default: 1000]
 //!   -v, --version
 //!           Print version information
 //!   -h, --help
@@ -80,11 +144,21 @@
 //! | Option | Description |
 //! |--------|-------------|
 //! | `-p, --port <port>` | The port to bind to. If not specified, a random port will be allocated by the operating system. |
+//! | `--host <host>` | The address to bind to. Accepts an IPv4 or IPv6 address, or `unix:<path>` for a Unix domain socket. Defaults to `0.0.0.0`. |
+//! | `--tls-cert <tls-cert>` | PEM certificate chain to serve HTTPS with. Requires `--tls-key`. |
+//! | `--tls-key <tls-key>` | PEM private key for the certificate given with `--tls-cert`. |
+//! | `--self-signed` | Serve HTTPS using a self-signed certificate generated at startup. |
+//! | `--shutdown-timeout <shutdown-timeout>` | Seconds to wait for in-flight requests to complete when shutting down. Defaults to 10. |
+//! | `--port-file <port-file>` | File to write the bound port to once the server is listening. |
+//! | `--print-port` | Print `{"port": ..., "host": ...}` to stdout as a single JSON line once the server is listening. Logs are written to stderr instead. |
 //!
 
 #![warn(missing_docs)]
 
+use std::collections::BTreeSet;
 use std::env;
+use std::fs;
+use std::io::Write;
 use std::process::ExitCode;
 use std::str::FromStr;
 use std::path::PathBuf;
@@ -92,12 +166,14 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::sync::mpsc::channel;
 
+use anyhow::Context;
 use clap::{Command, Arg, ArgMatches, ArgAction, command, crate_version};
 use clap::error::ErrorKind;
+use itertools::Itertools;
 use mimalloc::MiMalloc;
 use pact_models::prelude::*;
-use pact_models::prelude::v4::*;
 use regex::Regex;
+use serde_json::{json, Value};
 use tracing::{debug, error, info, warn};
 use tracing_core::LevelFilter;
 use tracing_subscriber::FmtSubscriber;
@@ -105,14 +181,21 @@ use tokio::sync::broadcast;
 use notify::RecursiveMode;
 use notify_debouncer_mini::{DebouncedEventKind, new_debouncer};
 use crate::loading::load_pacts;
+use crate::plugins::PluginMockServer;
 
-use crate::server::ServerHandler;
+use crate::server::{ListenOptions, ServerHandler, ServerOptions, SharedSources, UnmatchedResponse, shutdown_signal};
+
+pub use crate::builder::{StubServerBuilder, StubServerHandle};
+pub use crate::listener::{BindAddress, LocalAddress};
+pub use crate::recorder::RecordOptions;
+pub use crate::strategy::{MatchStrategy, SequenceMode};
+pub use crate::tls::TlsConfig;
 
 /// Setup file watcher for watch mode
 fn setup_file_watcher(
   sources: Vec<PactSource>,
   matches: &ArgMatches,
-  shared_pacts: Arc<Mutex<Vec<(V4Pact, PactSource)>>>,
+  shared_pacts: SharedSources,
   reload_tx: broadcast::Sender<()>
 ) {
   let watch_paths = get_watch_paths(&sources);
@@ -149,32 +232,40 @@ fn setup_file_watcher(
       match debounce_rx.recv() {
         Ok(Ok(events)) => {
           for event in events.iter() {
-            match &event.kind {
-              DebouncedEventKind::Any => {
-                info!("File change detected in watched directory");
+            if event.kind == DebouncedEventKind::Any {
+              info!("File change detected in watched directory");
+              
+              // Reload pacts
+              let pacts_result = runtime.block_on(load_pacts(sources.clone(), insecure_tls, ext.as_ref()));
+              if pacts_result.iter().any(|p| p.is_err()) {
+                error!("Error reloading pacts:");
+                for error in pacts_result.iter().filter_map(|p| p.as_ref().err()) {
+                  error!("  - {}", error);
+                }
+              } else {
+                let new_pacts = pacts_result.iter()
+                  .filter_map(|result| result.as_ref().ok())
+                  .map(|(p, s)| (p.as_v4_pact().unwrap(), s.clone()))
+                  .collect::<Vec<_>>();
                 
-                // Reload pacts
-                let pacts_result = runtime.block_on(load_pacts(sources.clone(), insecure_tls, ext.as_ref()));
-                if pacts_result.iter().any(|p| p.is_err()) {
-                  error!("Error reloading pacts:");
-                  for error in pacts_result.iter().filter_map(|p| p.as_ref().err()) {
-                    error!("  - {}", error);
-                  }
-                } else {
-                  let new_pacts = pacts_result.iter()
-                    .filter_map(|result| result.as_ref().ok())
-                    .map(|(p, s)| (p.as_v4_pact().unwrap(), s.clone()))
-                    .collect::<Vec<_>>();
-                  
-                  let interactions: usize = new_pacts.iter().map(|(p, _)| p.interactions.len()).sum();
-                  info!("Reloaded {} pacts ({} total interactions)", new_pacts.len(), interactions);
-                  
-                  *shared_pacts.lock().unwrap() = new_pacts;
-                  let _ = reload_tx.send(());
+                let interactions: usize = new_pacts.iter().map(|(p, _)| p.interactions.len()).sum();
+                info!("Reloaded {} pacts ({} total interactions)", new_pacts.len(), interactions);
+                let transports = new_pacts.iter().flat_map(|(p, _)| plugins::plugin_transports(p)).collect::<BTreeSet<_>>();
+                if !transports.is_empty() {
+                  warn!("Plugin mock servers are not restarted when pacts are reloaded, changes to interactions using the {} transport(s) will not be served",
+                    transports.iter().join(", "));
                 }
-                break;
+                
+                // Pacts uploaded through the admin API are not backed by files, so keep them
+                let mut pacts = shared_pacts.lock().unwrap();
+                let uploaded = pacts.iter()
+                  .filter(|(_, s)| matches!(s, PactSource::Uploaded(_)))
+                  .cloned()
+                  .collect::<Vec<_>>();
+                *pacts = new_pacts.into_iter().chain(uploaded).collect();
+                let _ = reload_tx.send(());
               }
-              _ => {}
+              break;
             }
           }
         }
@@ -202,14 +293,29 @@ fn get_watch_paths(sources: &[PactSource]) -> Vec<PathBuf> {
     .collect()
 }
 
+mod admin;
+mod builder;
+mod events;
+mod journal;
 mod pact_support;
+mod plugins;
+mod proxy;
+mod recorder;
+mod scenario;
 mod server;
+mod strategy;
+mod listener;
 mod loading;
+mod matching;
+mod messages;
+mod tls;
+mod websocket;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
 
+/// Prints the version of the stub server and the Pact specification it supports
 pub fn print_version() {
     println!("pact stub server version  : v{}", env!("CARGO_PKG_VERSION"));
     println!("pact specification version: v{}", PactSpecification::V4.version_str());
@@ -219,6 +325,43 @@ fn integer_value(v: &str) -> Result<u16, String> {
     v.parse::<u16>().map_err(|e| format!("'{}' is not a valid port value: {}", v, e) )
 }
 
+fn host_value(v: &str) -> Result<BindAddress, String> {
+  BindAddress::from_str(v)
+}
+
+fn url_value(v: &str) -> Result<reqwest::Url, String> {
+  reqwest::Url::parse(v)
+    .map_err(|err| format!("'{}' is not a valid URL: {}", v, err))
+    .and_then(|url| if url.scheme() == "http" || url.scheme() == "https" {
+      Ok(url)
+    } else {
+      Err(format!("'{}' is not a HTTP or HTTPS URL", v))
+    })
+}
+
+fn method_value(v: &str) -> Result<String, String> {
+  if v == "*" || (!v.is_empty() && v.chars().all(|c| c.is_ascii_alphabetic() || c == '-' || c == '_')) {
+    Ok(v.to_uppercase())
+  } else {
+    Err(format!("'{}' is not a valid HTTP method", v))
+  }
+}
+
+fn state_param_value(v: &str) -> Result<(String, serde_json::Value), String> {
+  match v.split_once('=') {
+    Some((name, value)) if !name.is_empty() => Ok((name.to_string(),
+      serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string())))),
+    _ => Err(format!("'{}' is not a valid provider state parameter, it must be in the form name=value", v))
+  }
+}
+
+fn header_value(v: &str) -> Result<(String, String), String> {
+  match v.split_once(':') {
+    Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+    _ => Err(format!("'{}' is not a valid header, it must be in the form 'Name: value'", v))
+  }
+}
+
 fn regex_value(v: &str) -> Result<Regex, String> {
   if v.is_empty() {
     Err("Regular expression is empty".to_string())
@@ -247,10 +390,56 @@ pub enum PactSource {
     /// Provider names to filter Pacts with
     providers: Vec<Regex>
   },
+  /// Pact uploaded through the admin API, with the ID it was uploaded with
+  Uploaded(String),
+  /// Pact supplied directly through the library API
+  Embedded,
   /// Source that is not known, only used for unit testing
   Unknown
 }
 
+fn tls_config(matches: &ArgMatches) -> Option<TlsConfig> {
+  if matches.get_flag("self-signed") {
+    Some(TlsConfig::SelfSigned)
+  } else {
+    match (matches.get_one::<PathBuf>("tls-cert"), matches.get_one::<PathBuf>("tls-key")) {
+      (Some(cert), Some(key)) => Some(TlsConfig::Pem { cert: cert.clone(), key: key.clone() }),
+      _ => None
+    }
+  }
+}
+
+fn unmatched_response(matches: &ArgMatches) -> anyhow::Result<UnmatchedResponse> {
+  let body = matches.get_one::<PathBuf>("unmatched-body-file")
+    .map(|path| fs::read(path).with_context(|| format!("Failed to read unmatched body file {}", path.display())))
+    .transpose()?;
+  Ok(UnmatchedResponse {
+    status: *matches.get_one::<u16>("unmatched-status").unwrap_or(&404),
+    headers: matches.get_many::<(String, String)>("unmatched-header")
+      .map(|headers| headers.cloned().collect())
+      .unwrap_or_default(),
+    body: body.map(|body| body.into())
+  })
+}
+
+fn record_options(matches: &ArgMatches) -> Option<RecordOptions> {
+  matches.get_one::<PathBuf>("record").map(|pact_file| RecordOptions {
+    pact_file: pact_file.clone(),
+    upstream: matches.get_one::<reqwest::Url>("record-upstream").cloned().unwrap(),
+    consumer: matches.get_one::<String>("record-consumer").cloned().unwrap_or_default(),
+    provider: matches.get_one::<String>("record-provider").cloned().unwrap_or_default()
+  })
+}
+
+fn admin_prefix(matches: &ArgMatches) -> Option<String> {
+  if matches.get_flag("admin") {
+    let prefix = matches.get_one::<String>("admin-prefix").map(|p| p.as_str()).unwrap_or("/__pact");
+    Some(format!("/{}", prefix.trim_matches('/')))
+  } else {
+    None
+  }
+}
+
 fn pact_source(matches: &ArgMatches) -> Vec<PactSource> {
   let mut sources = vec![];
 
@@ -286,8 +475,8 @@ fn pact_source(matches: &ArgMatches) -> Vec<PactSource> {
     sources.push(PactSource::Broker {
       url: url.to_string(),
       auth,
-      consumers: matches.get_many::<Regex>("consumer-name").unwrap_or_default().into_iter().cloned().collect(),
-      providers: matches.get_many::<Regex>("provider-name").unwrap_or_default().into_iter().cloned().collect()
+      consumers: matches.get_many::<Regex>("consumer-name").unwrap_or_default().cloned().collect(),
+      providers: matches.get_many::<Regex>("provider-name").unwrap_or_default().cloned().collect()
     });
   }
 
@@ -334,7 +523,7 @@ pub fn process_stub_command(args: &ArgMatches) -> Result<(), ExitCode>  {
 async fn handle_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
       let level = matches.get_one::<String>("loglevel").cloned()
         .unwrap_or_else(|| "info".to_string());
-      setup_logger(level.as_str());
+      setup_logger(level.as_str(), matches.get_flag("print-port"));
       let sources = pact_source(matches);
       let watch_mode = matches.get_flag("watch");
 
@@ -353,9 +542,52 @@ async fn handle_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
         Err(ExitCode::from(3))
       } else {
         let port = *matches.get_one::<u16>("port").unwrap_or(&0);
-        let provider_state = matches.get_one::<Regex>("provider-state").cloned();
-        let provider_state_header_name = matches.get_one::<String>("provider-state-header-name").cloned();
-        let empty_provider_states = matches.get_flag("empty-provider-state");
+        let listen_options = ListenOptions {
+          host: matches.get_one::<BindAddress>("host").cloned().unwrap_or_default(),
+          port,
+          tls: tls_config(matches),
+          shutdown_timeout: Duration::from_secs(*matches.get_one::<u64>("shutdown-timeout").unwrap_or(&10))
+        };
+        let unmatched_response = match unmatched_response(matches) {
+          Ok(response) => response,
+          Err(err) => {
+            error!("{:#}", err);
+            return Err(ExitCode::FAILURE);
+          }
+        };
+        let server_options = ServerOptions {
+          auto_cors: matches.get_flag("cors"),
+          cors_referer: matches.get_flag("cors-referer"),
+          provider_state: matches.get_one::<Regex>("provider-state").cloned(),
+          provider_state_header_name: matches.get_one::<String>("provider-state-header-name").cloned(),
+          provider_state_header_literal: matches.get_flag("provider-state-header-literal"),
+          provider_state_params: matches.get_many::<(String, serde_json::Value)>("provider-state-param")
+            .map(|params| params.cloned().collect())
+            .unwrap_or_default(),
+          provider_state_params_header_name: matches.get_one::<String>("provider-state-params-header-name").cloned(),
+          empty_provider_states: matches.get_flag("empty-provider-state"),
+          explain_mismatches: matches.get_flag("explain-mismatches"),
+          match_strategy: matches.get_one::<String>("match-strategy")
+            .map(|strategy| strategy.parse().unwrap())
+            .unwrap_or_default(),
+          sequence: matches.get_one::<String>("sequence").map(|mode| mode.parse().unwrap()),
+          strict_headers: matches.get_flag("strict-headers"),
+          strict_header_names: matches.get_many::<String>("strict-header")
+            .map(|names| names.cloned().collect())
+            .unwrap_or_default(),
+          body_methods: matches.get_many::<String>("body-methods")
+            .map(|methods| methods.cloned().collect()),
+          unmatched_response,
+          proxy_url: matches.get_one::<reqwest::Url>("proxy-url").cloned(),
+          record: record_options(matches),
+          scenario: matches.get_one::<String>("scenario").cloned(),
+          message_callback_url: matches.get_one::<reqwest::Url>("message-callback-url").cloned(),
+          websocket_path: matches.get_one::<String>("websocket-path").cloned(),
+          websocket_interval: matches.get_one::<u64>("websocket-interval").map(|ms| Duration::from_millis(*ms)),
+          event_delay: matches.get_one::<u64>("event-delay").map(|ms| Duration::from_millis(*ms)),
+          admin_prefix: admin_prefix(matches),
+          journal_size: matches.get_one::<usize>("journal-size").copied()
+        };
         let pacts = pacts.iter()
           .map(|result| {
             // Currently, as_v4_pact won't fail as it upgrades older formats to V4, so is safe to unwrap
@@ -365,43 +597,92 @@ async fn handle_matches(matches: &ArgMatches) -> Result<(), ExitCode> {
           .collect::<Vec<_>>();
         let interactions: usize = pacts.iter().map(|(p, _)| p.interactions.len()).sum();
         info!("Loaded {} pacts ({} total interactions)", pacts.len(), interactions);
-        let auto_cors = matches.get_flag("cors");
-        let referer = matches.get_flag("cors-referer");
-        
-        if watch_mode {
+        let plugin_host = match &listen_options.host {
+          BindAddress::Ip(ip) => Some(ip.to_string()),
+          #[cfg(unix)]
+          BindAddress::Unix(_) => None
+        };
+        let listener = match server::bind(listen_options).await {
+          Ok(listener) => listener,
+          Err(err) => {
+            error!("{:#}", err);
+            return Err(ExitCode::FAILURE);
+          }
+        };
+        // Started once the listener is bound, and shut down on every exit path after this
+        let plugins_required = pacts.iter().any(|(pact, _)| pact.requires_plugins());
+        let plugin_servers = plugins::start_mock_servers(&pacts, plugin_host).await;
+
+        let server_handler = if watch_mode {
           // Setup shared state for pacts when in watch mode
           let shared_pacts = Arc::new(Mutex::new(pacts.clone()));
-          let (reload_tx, reload_rx) = broadcast::channel::<()>(1);
+          let (reload_tx, _reload_rx) = broadcast::channel::<()>(1);
           
           // Setup file watching if in watch mode
           setup_file_watcher(sources, matches, shared_pacts.clone(), reload_tx.clone());
           
-          let server_handler = ServerHandler::new_with_watch(
-            shared_pacts,
-            reload_tx,
-            auto_cors,
-            referer,
-            provider_state,
-            provider_state_header_name,
-            empty_provider_states);
-          tokio::task::spawn_blocking(move || {
-            server_handler.start_server(port)
-          }).await.unwrap()
+          ServerHandler::new_with_watch(shared_pacts, reload_tx, server_options)
         } else {
-          let server_handler = ServerHandler::new(
-            pacts,
-            auto_cors,
-            referer,
-            provider_state,
-            provider_state_header_name,
-            empty_provider_states);
-          tokio::task::spawn_blocking(move || {
-            server_handler.start_server(port)
-          }).await.unwrap()
+          ServerHandler::new(pacts, server_options)
+        };
+
+        let result = match report_bound_address(matches, listener.local_address(), &plugin_servers) {
+          Ok(()) => server_handler.serve(listener, shutdown_signal()).await,
+          Err(err) => {
+            error!("{:#}", err);
+            Err(ExitCode::FAILURE)
+          }
+        };
+        // The plugins are loaded even if none of their mock servers started
+        if plugins_required || !plugin_servers.is_empty() {
+          plugins::shutdown_mock_servers(&plugin_servers).await;
         }
+        result
       }
 }
 
+/// Writes the bound address to the port file and/or stdout so test harnesses can discover it.
+/// The ports of any plugin mock servers are included in the JSON written to stdout.
+fn report_bound_address(
+  matches: &ArgMatches,
+  address: &LocalAddress,
+  plugin_servers: &[PluginMockServer]
+) -> anyhow::Result<()> {
+  if let Some(path) = matches.get_one::<PathBuf>("port-file") {
+    let contents = address.port()
+      .map(|port| port.to_string())
+      .unwrap_or_else(|| address.to_string());
+    // Write to a temporary file first so readers never see a partially written value
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::write(&tmp_path, contents)
+      .and_then(|_| fs::rename(&tmp_path, path))
+      .with_context(|| format!("Failed to write the port file {}", path.display()))?;
+    debug!("Wrote bound port to {}", path.display());
+  }
+
+  if matches.get_flag("print-port") {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", bound_address_json(address, plugin_servers)).and_then(|_| stdout.flush())?;
+  }
+
+  Ok(())
+}
+
+/// JSON written to stdout with the bound address and the ports of any plugin mock servers
+fn bound_address_json(address: &LocalAddress, plugin_servers: &[PluginMockServer]) -> Value {
+  let mut json = match address {
+    LocalAddress::Tcp(addr) => json!({ "port": addr.port(), "host": addr.ip().to_string() }),
+    #[cfg(unix)]
+    LocalAddress::Unix(_) => json!({ "port": null, "host": address.to_string() })
+  };
+  if !plugin_servers.is_empty() {
+    json["plugins"] = plugin_servers.iter().map(|server| server.to_json()).collect();
+  }
+  json
+}
+
 /// Creates a new clap Command instance with the command line arguments for the stub server.
 /// This function defines the command line interface for the stub server, including options for logging, pact file sources, and server configuration.
 pub fn build_args() -> Command {
@@ -418,14 +699,14 @@ pub fn build_args() -> Command {
     .arg(Arg::new("file")
       .short('f')
       .long("file")
-      .required_unless_present_any(&["dir", "url", "broker-url"])
+      .required_unless_present_any(["dir", "url", "broker-url", "record"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Pact file to load (can be repeated)"))
     .arg(Arg::new("dir")
       .short('d')
       .long("dir")
-      .required_unless_present_any(&["file", "url", "broker-url"])
+      .required_unless_present_any(["file", "url", "broker-url", "record"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Directory of pact files to load (can be repeated)"))
@@ -438,7 +719,7 @@ pub fn build_args() -> Command {
     .arg(Arg::new("url")
       .short('u')
       .long("url")
-      .required_unless_present_any(&["file", "dir", "broker-url"])
+      .required_unless_present_any(["file", "dir", "broker-url", "record"])
       .action(ArgAction::Append)
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("URL of pact file to fetch (can be repeated)"))
@@ -446,7 +727,7 @@ pub fn build_args() -> Command {
       .short('b')
       .long("broker-url")
       .env("PACT_BROKER_BASE_URL")
-      .required_unless_present_any(&["file", "dir", "url"])
+      .required_unless_present_any(["file", "dir", "url", "record"])
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("URL of the pact broker to fetch pacts from"))
     .arg(Arg::new("user")
@@ -466,6 +747,39 @@ pub fn build_args() -> Command {
       .use_value_delimiter(false)
       .help("Port to run on (defaults to random port assigned by the OS)")
       .value_parser(integer_value))
+    .arg(Arg::new("host")
+      .long("host")
+      .default_value("0.0.0.0")
+      .help("Address to bind to. Accepts an IPv4 or IPv6 address, or unix:<path> for a Unix domain socket")
+      .value_parser(host_value))
+    .arg(Arg::new("tls-cert")
+      .long("tls-cert")
+      .requires("tls-key")
+      .conflicts_with("self-signed")
+      .value_parser(clap::value_parser!(PathBuf))
+      .help("PEM file containing the certificate chain to serve HTTPS with"))
+    .arg(Arg::new("tls-key")
+      .long("tls-key")
+      .requires("tls-cert")
+      .value_parser(clap::value_parser!(PathBuf))
+      .help("PEM file containing the private key for the certificate given with --tls-cert"))
+    .arg(Arg::new("self-signed")
+      .long("self-signed")
+      .action(ArgAction::SetTrue)
+      .help("Serve HTTPS using a self-signed certificate generated at startup"))
+    .arg(Arg::new("shutdown-timeout")
+      .long("shutdown-timeout")
+      .default_value("10")
+      .value_parser(clap::value_parser!(u64))
+      .help("Seconds to wait for in-flight requests to complete when shutting down"))
+    .arg(Arg::new("port-file")
+      .long("port-file")
+      .value_parser(clap::value_parser!(PathBuf))
+      .help("File to write the bound port to once the server is listening"))
+    .arg(Arg::new("print-port")
+      .long("print-port")
+      .action(ArgAction::SetTrue)
+      .help("Print the bound port and host to stdout as a JSON line once the server is listening, and log to stderr"))
     .arg(Arg::new("cors")
       .short('o')
       .long("cors")
@@ -490,11 +804,101 @@ pub fn build_args() -> Command {
       .value_parser(clap::builder::NonEmptyStringValueParser::new())
       .help("Name of the header parameter containing the provider state to be used in case \
       multiple matching interactions are found"))
+    .arg(Arg::new("provider-state-header-literal")
+      .long("provider-state-header-literal")
+      .requires("provider-state-header-name")
+      .action(ArgAction::SetTrue)
+      .help("Match the provider state header value exactly, instead of as a regular expression"))
+    .arg(Arg::new("provider-state-param")
+      .long("provider-state-param")
+      .action(ArgAction::Append)
+      .value_parser(state_param_value)
+      .help("Provider state parameter to filter the responses by, in the form name=value (can be repeated). \
+      Values are parsed as JSON if possible"))
+    .arg(Arg::new("provider-state-params-header-name")
+      .long("provider-state-params-header-name")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Name of the header containing a JSON object of provider state parameters to filter the responses by"))
     .arg(Arg::new("empty-provider-state")
       .long("empty-provider-state")
       .requires("provider-state")
       .action(ArgAction::SetTrue)
       .help("Include empty provider states when filtering with --provider-state"))
+    .arg(Arg::new("explain-mismatches")
+      .long("explain-mismatches")
+      .action(ArgAction::SetTrue)
+      .help("Return a JSON body describing the closest interactions and their mismatches when no interaction matches a request"))
+    .arg(Arg::new("match-strategy")
+      .long("match-strategy")
+      .default_value("fewest-mismatches")
+      .value_parser(MatchStrategy::NAMES)
+      .help("How to select the interaction when more than one matches a request"))
+    .arg(Arg::new("sequence")
+      .long("sequence")
+      .value_parser(SequenceMode::NAMES)
+      .conflicts_with("match-strategy")
+      .help("Use the matching interactions in the order they were loaded for repeated requests with the same method, path and query, either cycling back to the first or staying on the last"))
+    .arg(Arg::new("strict-headers")
+      .long("strict-headers")
+      .action(ArgAction::SetTrue)
+      .help("Only use interactions whose expected headers all match the request"))
+    .arg(Arg::new("strict-header")
+      .long("strict-header")
+      .action(ArgAction::Append)
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Only use interactions whose expected value for this header matches the request (can be repeated)"))
+    .arg(Arg::new("body-methods")
+      .long("body-methods")
+      .value_delimiter(',')
+      .default_value("POST,PUT,PATCH,QUERY")
+      .value_parser(method_value)
+      .help("Comma-separated methods whose request bodies must match the interaction, or * for all methods"))
+    .arg(Arg::new("unmatched-status")
+      .long("unmatched-status")
+      .default_value("404")
+      .value_parser(clap::value_parser!(u16).range(100..600))
+      .help("Status code to return when no interaction matches a request"))
+    .arg(Arg::new("unmatched-body-file")
+      .long("unmatched-body-file")
+      .value_parser(clap::value_parser!(PathBuf))
+      .help("File containing the body to return when no interaction matches a request"))
+    .arg(Arg::new("unmatched-header")
+      .long("unmatched-header")
+      .action(ArgAction::Append)
+      .value_parser(header_value)
+      .help("Header to add to the response when no interaction matches a request, in the form 'Name: value' (can be repeated)"))
+    .arg(Arg::new("proxy-url")
+      .long("proxy-url")
+      .value_parser(url_value)
+      .help("URL to forward requests to when no interaction matches them"))
+    .arg(Arg::new("record")
+      .long("record")
+      .requires("record-upstream")
+      .conflicts_with("proxy-url")
+      .value_parser(clap::value_parser!(PathBuf))
+      .help("Pact file to record requests that no interaction matches into, after forwarding them to --record-upstream"))
+    .arg(Arg::new("record-upstream")
+      .long("record-upstream")
+      .requires("record")
+      .value_parser(url_value)
+      .help("URL of the provider to forward requests to when recording"))
+    .arg(Arg::new("record-consumer")
+      .long("record-consumer")
+      .requires("record")
+      .default_value("consumer")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Consumer name to use for the recorded pact"))
+    .arg(Arg::new("record-provider")
+      .long("record-provider")
+      .requires("record")
+      .default_value("provider")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Provider name to use for the recorded pact"))
+    .arg(Arg::new("scenario")
+      .long("scenario")
+      .conflicts_with("provider-state")
+      .help("Select interactions by the current state of a scenario, starting in this provider state. \
+      Matched interactions move the scenario to the state given by their 'nextState' comment"))
     .arg(Arg::new("consumer-name")
       .long("consumer-name")
       .alias("consumer-names")
@@ -509,6 +913,39 @@ pub fn build_args() -> Command {
       .action(ArgAction::Append)
       .value_parser(regex_value)
       .help("Provider name or regex to use to filter the Pacts fetched from the Pact broker (can be repeated)"))
+    .arg(Arg::new("admin")
+      .long("admin")
+      .action(ArgAction::SetTrue)
+      .help("Enable the admin API for inspecting and controlling the running server"))
+    .arg(Arg::new("admin-prefix")
+      .long("admin-prefix")
+      .default_value("/__pact")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Path prefix the admin API is served under"))
+    .arg(Arg::new("journal-size")
+      .long("journal-size")
+      .default_value("1000")
+      .value_parser(clap::value_parser!(usize))
+      .help("Maximum number of requests to keep in the admin API request journal"))
+    .arg(Arg::new("message-callback-url")
+      .long("message-callback-url")
+      .requires("admin")
+      .value_parser(url_value)
+      .help("URL to POST messages triggered through the admin API to, instead of returning them"))
+    .arg(Arg::new("websocket-path")
+      .long("websocket-path")
+      .value_parser(clap::builder::NonEmptyStringValueParser::new())
+      .help("Path to accept WebSocket connections on, which asynchronous messages are pushed to"))
+    .arg(Arg::new("websocket-interval")
+      .long("websocket-interval")
+      .requires("websocket-path")
+      .value_parser(clap::value_parser!(u64).range(1..))
+      .help("Milliseconds between pushing the next asynchronous message to the WebSocket clients"))
+    .arg(Arg::new("event-delay")
+      .long("event-delay")
+      .default_value("1000")
+      .value_parser(clap::value_parser!(u64))
+      .help("Milliseconds between the events of Server-Sent Events responses"))
     .arg(Arg::new("watch")
       .short('w')
       .long("watch")
@@ -521,17 +958,22 @@ pub fn build_args() -> Command {
       .help("Print version information"))
 }
 
-fn setup_logger(level: &str) {
+/// Logs go to stdout, unless it is reserved for the JSON line printed by `--print-port`
+fn setup_logger(level: &str, to_stderr: bool) {
   let log_level = match level {
     "none" => LevelFilter::OFF,
     _ => LevelFilter::from_str(level).unwrap_or(LevelFilter::INFO)
   };
-  let subscriber = FmtSubscriber::builder()
+  let builder = FmtSubscriber::builder()
     .compact()
     .with_max_level(log_level)
-    .with_thread_names(true)
-    .finish();
-  if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
+    .with_thread_names(true);
+  let result = if to_stderr {
+    tracing::subscriber::set_global_default(builder.with_writer(std::io::stderr).finish())
+  } else {
+    tracing::subscriber::set_global_default(builder.finish())
+  };
+  if let Err(err) = result {
     eprintln!("ERROR: Failed to initialise global tracing subscriber - {err}");
   };
 }
@@ -0,0 +1,184 @@
+//! Addresses and listeners the stub server can accept connections on
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)] use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)] use tokio::net::{UnixListener, UnixStream};
+
+/// Address the server binds to
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindAddress {
+  /// IPv4 or IPv6 interface address. The port is supplied separately.
+  Ip(IpAddr),
+  /// Unix domain socket path
+  #[cfg(unix)]
+  Unix(PathBuf)
+}
+
+impl Default for BindAddress {
+  fn default() -> Self {
+    BindAddress::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+  }
+}
+
+impl FromStr for BindAddress {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(path) = s.strip_prefix("unix:") {
+      if path.is_empty() {
+        return Err("Unix domain socket path is empty".to_string());
+      }
+      #[cfg(unix)]
+      return Ok(BindAddress::Unix(PathBuf::from(path)));
+      #[cfg(not(unix))]
+      return Err("Unix domain sockets are not supported on this platform".to_string());
+    }
+
+    let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    IpAddr::from_str(ip)
+      .map(BindAddress::Ip)
+      .map_err(|err| format!("'{}' is not a valid IP address or unix:<path> value: {}", s, err))
+  }
+}
+
+impl Display for BindAddress {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BindAddress::Ip(ip) => write!(f, "{}", ip),
+      #[cfg(unix)]
+      BindAddress::Unix(path) => write!(f, "unix:{}", path.display())
+    }
+  }
+}
+
+/// Address a bound listener is accepting connections on
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalAddress {
+  /// TCP socket address, with the port assigned by the OS if none was requested
+  Tcp(SocketAddr),
+  /// Unix domain socket path
+  #[cfg(unix)]
+  Unix(PathBuf)
+}
+
+impl LocalAddress {
+  /// The socket address if listening on TCP
+  pub fn socket_addr(&self) -> Option<SocketAddr> {
+    match self {
+      LocalAddress::Tcp(addr) => Some(*addr),
+      #[cfg(unix)]
+      LocalAddress::Unix(_) => None
+    }
+  }
+
+  /// The port if listening on TCP
+  pub fn port(&self) -> Option<u16> {
+    self.socket_addr().map(|addr| addr.port())
+  }
+}
+
+impl Display for LocalAddress {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LocalAddress::Tcp(addr) => write!(f, "{}", addr),
+      #[cfg(unix)]
+      LocalAddress::Unix(path) => write!(f, "unix:{}", path.display())
+    }
+  }
+}
+
+/// A bound listener that connections can be accepted from
+pub trait Listener: Send + 'static {
+  /// Connection stream type
+  type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+  /// Accepts the next connection, returning the stream and a description of the peer
+  fn accept(&self) -> impl Future<Output = io::Result<(Self::Io, String)>> + Send;
+}
+
+impl Listener for TcpListener {
+  type Io = TcpStream;
+
+  async fn accept(&self) -> io::Result<(Self::Io, String)> {
+    TcpListener::accept(self).await.map(|(stream, addr)| (stream, addr.to_string()))
+  }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+  type Io = UnixStream;
+
+  async fn accept(&self) -> io::Result<(Self::Io, String)> {
+    UnixListener::accept(self).await.map(|(stream, addr)| {
+      let peer = addr.as_pathname()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|| "unix socket peer".to_string());
+      (stream, peer)
+    })
+  }
+}
+
+/// Binds a TCP listener to the IP address and port
+pub async fn bind_tcp(ip: IpAddr, port: u16) -> io::Result<TcpListener> {
+  TcpListener::bind(SocketAddr::new(ip, port)).await
+}
+
+/// Binds a Unix domain socket listener, removing any stale socket file left at the path
+#[cfg(unix)]
+pub fn bind_unix(path: &PathBuf) -> io::Result<UnixListener> {
+  use std::os::unix::fs::FileTypeExt;
+
+  if let Ok(metadata) = std::fs::metadata(path) && metadata.file_type().is_socket() {
+    tracing::warn!("Removing existing socket file {}", path.display());
+    std::fs::remove_file(path)?;
+  }
+  UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv6Addr;
+
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn parses_ipv4_addresses() {
+    expect!(BindAddress::from_str("127.0.0.1")).to(be_ok().value(BindAddress::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+    expect!(BindAddress::from_str("0.0.0.0")).to(be_ok().value(BindAddress::default()));
+  }
+
+  #[test]
+  fn parses_ipv6_addresses_with_or_without_brackets() {
+    expect!(BindAddress::from_str("::1")).to(be_ok().value(BindAddress::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST))));
+    expect!(BindAddress::from_str("[::]")).to(be_ok().value(BindAddress::Ip(IpAddr::V6(Ipv6Addr::UNSPECIFIED))));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn parses_unix_socket_paths() {
+    expect!(BindAddress::from_str("unix:/tmp/stub.sock")).to(be_ok().value(BindAddress::Unix(PathBuf::from("/tmp/stub.sock"))));
+    expect!(BindAddress::from_str("unix:")).to(be_err());
+  }
+
+  #[test]
+  fn rejects_invalid_addresses() {
+    expect!(BindAddress::from_str("localhost")).to(be_err());
+    expect!(BindAddress::from_str("1.2.3")).to(be_err());
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn display_round_trips() {
+    let address = BindAddress::from_str("unix:/tmp/stub.sock").unwrap();
+    expect!(BindAddress::from_str(address.to_string().as_str())).to(be_ok().value(address));
+  }
+}
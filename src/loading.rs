@@ -71,11 +71,13 @@ impl From<anyhow::Error> for PactError {
   }
 }
 
+type LoadedPact = Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>;
+
 fn walkdir(
   dir: &Path,
   ext: &str,
   s: &PactSource
-) -> Result<Vec<Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>>, PactError> {
+) -> Result<Vec<LoadedPact>, PactError> {
   let mut pacts = vec![];
   debug!("Scanning {:?}", dir);
   for entry in fs::read_dir(dir)? {
@@ -128,7 +130,7 @@ pub async fn load_pacts(
   sources: Vec<PactSource>,
   insecure_tls: bool,
   ext: Option<&String>
-) -> Vec<Result<(Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactSource), PactError>> {
+) -> Vec<LoadedPact> {
   futures::stream::iter(sources)
     .then(| s| async move {
       let values = match &s {
@@ -194,7 +196,7 @@ pub async fn load_pacts(
             Err(err) => vec![Err(PactError::new(err.to_string()))]
           }
         }
-        PactSource::Unknown => vec![]
+        PactSource::Uploaded(_) | PactSource::Embedded | PactSource::Unknown => vec![]
       };
       futures::stream::iter(values)
     })
@@ -203,7 +205,7 @@ pub async fn load_pacts(
     .await
 }
 
-fn filter_providers(providers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
+fn filter_providers(providers: &[Regex], result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
   match result {
     Ok(pact) => {
       if providers.is_empty() {
@@ -217,7 +219,7 @@ fn filter_providers(providers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send
   }
 }
 
-fn filter_consumers(consumers: &Vec<Regex>, result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
+fn filter_consumers(consumers: &[Regex], result: &Result<Box<dyn Pact + Send + Sync + RefUnwindSafe>, PactError>) -> Ready<bool> {
   match result {
     Ok(pact) => {
       if consumers.is_empty() {
@@ -242,14 +244,14 @@ mod tests {
   #[tokio::test]
   async fn filter_consumers_with_error_result() {
     let result = Err(PactError::new("test".to_string()));
-    let filter_result = filter_consumers(&vec![Regex::new("one").unwrap()], &result).await;
+    let filter_result = filter_consumers(&[Regex::new("one").unwrap()], &result).await;
     expect!(filter_result).to(be_true());
   }
 
   #[tokio::test]
   async fn filter_consumers_with_no_consumers() {
     let result = Ok(RequestResponsePact::default().boxed());
-    let filter_result = filter_consumers(&vec![], &result).await;
+    let filter_result = filter_consumers(&[], &result).await;
     expect!(filter_result).to(be_true());
   }
 
@@ -292,14 +294,14 @@ mod tests {
   #[tokio::test]
   async fn filter_providers_with_error_result() {
     let result = Err(PactError::new("test".to_string()));
-    let filter_result = filter_providers(&vec![Regex::new("one").unwrap()], &result).await;
+    let filter_result = filter_providers(&[Regex::new("one").unwrap()], &result).await;
     expect!(filter_result).to(be_true());
   }
 
   #[tokio::test]
   async fn filter_providers_with_no_providers() {
     let result = Ok(RequestResponsePact::default().boxed());
-    let filter_result = filter_providers(&vec![], &result).await;
+    let filter_result = filter_providers(&[], &result).await;
     expect!(filter_result).to(be_true());
   }
 
@@ -0,0 +1,144 @@
+//! Configuration for matching requests against the interactions being served
+
+use std::collections::HashSet;
+
+use pact_matching::Mismatch;
+use pact_models::v4::http_parts::HttpRequest;
+
+use crate::strategy::Selector;
+
+/// Methods whose request bodies are matched by default
+pub const DEFAULT_BODY_METHODS: [&str; 4] = ["POST", "PUT", "PATCH", "QUERY"];
+
+/// Which mismatches disqualify a candidate interaction. Method, path and query mismatches always
+/// do, and the remaining mismatches only affect which candidate is preferred.
+#[derive(Debug, Clone)]
+pub struct MismatchPolicy {
+  strict_headers: bool,
+  strict_header_names: HashSet<String>,
+  body_methods: Option<HashSet<String>>
+}
+
+impl Default for MismatchPolicy {
+  fn default() -> Self {
+    MismatchPolicy::new::<&str>(false, &[])
+  }
+}
+
+impl MismatchPolicy {
+  /// Creates a policy where header mismatches disqualify a candidate, either for all headers or
+  /// only for the named ones
+  pub fn new<S: AsRef<str>>(strict_headers: bool, strict_header_names: &[S]) -> Self {
+    MismatchPolicy {
+      strict_headers,
+      strict_header_names: strict_header_names.iter().map(|name| name.as_ref().to_lowercase()).collect(),
+      body_methods: Some(DEFAULT_BODY_METHODS.iter().map(|method| method.to_string()).collect())
+    }
+  }
+
+  /// Sets the methods whose request bodies must match. A method of `*` matches the bodies of
+  /// requests with any method.
+  pub fn with_body_methods<S: AsRef<str>>(mut self, methods: &[S]) -> Self {
+    self.body_methods = if methods.iter().any(|method| method.as_ref() == "*") {
+      None
+    } else {
+      Some(methods.iter().map(|method| method.as_ref().to_uppercase()).collect())
+    };
+    self
+  }
+
+  /// If a mismatch of the header disqualifies a candidate
+  pub fn is_strict_header(&self, name: &str) -> bool {
+    self.strict_headers || self.strict_header_names.contains(&name.to_lowercase())
+  }
+
+  /// If the body of a request with the method must match the body of the interaction
+  pub fn matches_body(&self, method: &str) -> bool {
+    self.body_methods.as_ref()
+      .map(|methods| methods.contains(&method.to_uppercase()))
+      .unwrap_or(true)
+  }
+
+  /// If the mismatch means the candidate interaction can not be used for the request
+  pub fn disqualifies(&self, request: &HttpRequest, mismatch: &Mismatch) -> bool {
+    match mismatch {
+      Mismatch::MethodMismatch { .. } => true,
+      Mismatch::PathMismatch { .. } => true,
+      Mismatch::QueryMismatch { .. } => true,
+      Mismatch::BodyMismatch { .. } => self.matches_body(&request.method) && request.body.is_present(),
+      Mismatch::HeaderMismatch { key, .. } => self.is_strict_header(key),
+      _ => false
+    }
+  }
+}
+
+/// How requests are matched against the interactions, and how the interaction to respond with is
+/// selected
+#[derive(Debug, Default)]
+pub struct MatchConfig {
+  /// Mismatches that disqualify a candidate interaction
+  pub policy: MismatchPolicy,
+  /// Selects the interaction when more than one matches
+  pub selector: Selector,
+  /// Prefer interactions with a matching provider state over those included because their
+  /// provider state is empty. Set in scenario mode, where interactions without a provider state
+  /// are always available.
+  pub prefer_matching_states: bool
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::bodies::OptionalBody;
+
+  use super::*;
+
+  fn header_mismatch(key: &str) -> Mismatch {
+    Mismatch::HeaderMismatch {
+      key: key.to_string(),
+      expected: "Bearer 1234".to_string(),
+      actual: "".to_string(),
+      mismatch: "Expected a header 'Authorization' but was missing".to_string()
+    }
+  }
+
+  #[test]
+  fn header_mismatches_only_disqualify_strict_headers() {
+    let request = HttpRequest::default();
+    let mismatch = header_mismatch("Authorization");
+
+    expect!(MismatchPolicy::default().disqualifies(&request, &mismatch)).to(be_false());
+    expect!(MismatchPolicy::new::<&str>(true, &[]).disqualifies(&request, &mismatch)).to(be_true());
+    expect!(MismatchPolicy::new(false, &["authorization"]).disqualifies(&request, &mismatch)).to(be_true());
+    expect!(MismatchPolicy::new(false, &["Accept"]).disqualifies(&request, &mismatch)).to(be_false());
+  }
+
+  #[test]
+  fn body_mismatches_disqualify_requests_with_the_body_methods() {
+    let mismatch = Mismatch::BodyMismatch {
+      path: "$".to_string(),
+      expected: None,
+      actual: None,
+      mismatch: "Expected body 'a' but was 'b'".to_string()
+    };
+    let request = |method: &str| HttpRequest {
+      method: method.to_string(),
+      body: OptionalBody::Present("b".into(), None, None),
+      .. HttpRequest::default()
+    };
+
+    let policy = MismatchPolicy::default();
+    expect!(policy.disqualifies(&request("post"), &mismatch)).to(be_true());
+    expect!(policy.disqualifies(&request("QUERY"), &mismatch)).to(be_true());
+    expect!(policy.disqualifies(&request("DELETE"), &mismatch)).to(be_false());
+    expect!(policy.disqualifies(&HttpRequest { method: "POST".to_string(), .. HttpRequest::default() }, &mismatch)).to(be_false());
+
+    let policy = MismatchPolicy::default().with_body_methods(&["delete", "SEARCH"]);
+    expect!(policy.disqualifies(&request("DELETE"), &mismatch)).to(be_true());
+    expect!(policy.disqualifies(&request("SEARCH"), &mismatch)).to(be_true());
+    expect!(policy.disqualifies(&request("POST"), &mismatch)).to(be_false());
+
+    let policy = MismatchPolicy::default().with_body_methods(&["*"]);
+    expect!(policy.disqualifies(&request("GET"), &mismatch)).to(be_true());
+  }
+}
@@ -0,0 +1,339 @@
+//! Message interactions. Asynchronous messages are triggered through the admin API and either
+//! returned or delivered to a consumer callback URL, and synchronous messages are served as
+//! HTTP request/reply exchanges.
+
+use anyhow::{anyhow, Context};
+use maplit::hashmap;
+use pact_matching::Mismatch;
+use pact_matching::generators::{apply_generators_to_async_message, apply_generators_to_sync_message};
+use pact_models::bodies::OptionalBody;
+use pact_models::generators::GeneratorTestMode;
+use pact_models::http_parts::HttpPart;
+use pact_models::pact::Pact;
+use pact_models::prelude::v4::*;
+use pact_models::v4::async_message::AsynchronousMessage;
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::message_parts::MessageContents;
+use pact_models::v4::sync_message::SynchronousMessage;
+use pact_models::v4::V4InteractionType;
+use reqwest::Url;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::PactSource;
+
+/// Header containing the message metadata as a JSON object
+pub const METADATA_HEADER: &str = "Pact-Message-Metadata";
+
+/// Selects the message interaction to trigger, by description and/or provider state name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageSelector {
+  /// Description of the message interaction
+  pub description: Option<String>,
+  /// Name of one of the provider states of the message interaction
+  pub provider_state: Option<String>
+}
+
+impl MessageSelector {
+  /// Reads the selector from a JSON object with `description` and/or `providerState` strings
+  pub fn from_json(json: &Value) -> anyhow::Result<Self> {
+    let field = |name: &str| json.get(name).and_then(|value| value.as_str()).map(|value| value.to_string());
+    let selector = MessageSelector { description: field("description"), provider_state: field("providerState") };
+    if selector.description.is_none() && selector.provider_state.is_none() {
+      Err(anyhow!("Request body must be a JSON object with a 'description' and/or 'providerState' string"))
+    } else {
+      Ok(selector)
+    }
+  }
+
+  fn matches(&self, message: &AsynchronousMessage) -> bool {
+    self.description.as_ref().map(|description| *description == message.description).unwrap_or(true) &&
+      self.provider_state.as_ref()
+        .map(|state| message.provider_states.iter().any(|ps| ps.name == *state))
+        .unwrap_or(true)
+  }
+}
+
+/// Asynchronous message interactions, in the order the pacts were loaded
+pub fn async_messages(sources: &[(V4Pact, PactSource)]) -> Vec<AsynchronousMessage> {
+  sources.iter()
+    .flat_map(|(pact, _)| pact.filter_interactions(V4InteractionType::Asynchronous_Messages))
+    .filter_map(|i| i.as_v4_async_message())
+    .collect()
+}
+
+/// First asynchronous message interaction selected, in the order the pacts were loaded
+pub fn find_message(sources: &[(V4Pact, PactSource)], selector: &MessageSelector) -> Option<AsynchronousMessage> {
+  let messages = async_messages(sources).into_iter()
+    .filter(|message| selector.matches(message))
+    .collect::<Vec<_>>();
+  if messages.len() > 1 {
+    warn!("Found {} message interactions for {:?}, using the first one", messages.len(), selector);
+  }
+  messages.into_iter().next()
+}
+
+/// Contents of the message with its generators applied
+pub async fn generate_contents(message: &AsynchronousMessage) -> MessageContents {
+  apply_generators_to_async_message(message, &GeneratorTestMode::Provider, &hashmap!{}, &vec![], &hashmap!{}).await
+}
+
+fn content_type(contents: &MessageContents) -> Option<String> {
+  contents.message_content_type().map(|content_type| content_type.to_string())
+}
+
+/// Response with the message contents as the body, and the metadata in the
+/// `Pact-Message-Metadata` header
+pub fn message_response(contents: &MessageContents) -> HttpResponse {
+  let mut headers = hashmap!{
+    METADATA_HEADER.to_string() => vec![Value::Object(contents.metadata.clone().into_iter().collect()).to_string()]
+  };
+  if let Some(content_type) = content_type(contents) {
+    headers.insert("Content-Type".to_string(), vec![content_type]);
+  }
+  HttpResponse {
+    status: 200,
+    headers: Some(headers),
+    body: match &contents.contents {
+      OptionalBody::Present(..) => contents.contents.clone(),
+      _ => OptionalBody::Missing
+    },
+    .. HttpResponse::default()
+  }
+}
+
+/// Delivers triggered messages to the consumer by POSTing them to a callback URL
+#[derive(Debug, Clone)]
+pub struct MessageCallback {
+  client: reqwest::Client,
+  url: Url
+}
+
+impl MessageCallback {
+  /// Creates a callback that delivers messages to the URL
+  pub fn new(url: Url) -> anyhow::Result<Self> {
+    let client = reqwest::Client::builder()
+      .build()
+      .context("Failed to create the HTTP client for the message callback")?;
+    Ok(MessageCallback { client, url })
+  }
+
+  /// URL messages are delivered to
+  pub fn url(&self) -> &Url {
+    &self.url
+  }
+
+  /// POSTs the message contents to the callback URL, with the metadata in the
+  /// `Pact-Message-Metadata` header. Returns the status of the callback response.
+  pub async fn deliver(&self, description: &str, contents: &MessageContents) -> anyhow::Result<u16> {
+    info!("Delivering message '{}' to {}", description, self.url);
+    let metadata = Value::Object(contents.metadata.clone().into_iter().collect());
+    let mut builder = self.client.post(self.url.clone())
+      .header(METADATA_HEADER, metadata.to_string());
+    if let Some(content_type) = content_type(contents) {
+      builder = builder.header("Content-Type", content_type);
+    }
+    if let Some(body) = contents.contents.value() {
+      builder = builder.body(body);
+    }
+    let response = builder.send().await
+      .with_context(|| format!("Failed to deliver message '{}' to {}", description, self.url))?;
+    info!("Message '{}' delivered to {} with response {}", description, self.url, response.status());
+    Ok(response.status().as_u16())
+  }
+}
+
+/// JSON describing the delivery of a message to the callback URL
+pub fn delivery_json(description: &str, callback: &MessageCallback, status: u16) -> Value {
+  json!({
+    "description": description,
+    "callbackUrl": callback.url().as_str(),
+    "status": status
+  })
+}
+
+/// Contents of a request made to a synchronous message endpoint. The content type is taken from
+/// the `Content-Type` header, and the metadata from the `Pact-Message-Metadata` header.
+pub fn request_contents(request: &HttpRequest) -> anyhow::Result<MessageContents> {
+  let metadata = match request.lookup_header_value(METADATA_HEADER) {
+    Some(value) => match serde_json::from_str::<Value>(&value) {
+      Ok(Value::Object(metadata)) => metadata.into_iter().collect(),
+      _ => return Err(anyhow!("The {} header must be a JSON object", METADATA_HEADER))
+    },
+    None => Default::default()
+  };
+  let contents = match &request.body {
+    OptionalBody::Present(body, None, encoding) => OptionalBody::Present(body.clone(), request.content_type(), *encoding),
+    body => body.clone()
+  };
+  Ok(MessageContents { contents, metadata, .. MessageContents::default() })
+}
+
+/// Outcome of matching a request against the synchronous message interactions
+#[derive(Debug, Clone)]
+pub enum SyncMessageMatch {
+  /// There is no synchronous message interaction with the description
+  NotFound,
+  /// The request matched the request message of this interaction
+  Matched(Box<SynchronousMessage>),
+  /// The request did not match any of the interactions with the description
+  Mismatched(Vec<(SynchronousMessage, Vec<Mismatch>)>)
+}
+
+/// Matches the request contents against the request messages of the synchronous message
+/// interactions with the description, in the order the pacts were loaded
+pub async fn match_sync_message(
+  sources: &[(V4Pact, PactSource)],
+  description: &str,
+  contents: &MessageContents
+) -> SyncMessageMatch {
+  let candidates = sources.iter()
+    .flat_map(|(pact, _)| pact.filter_interactions(V4InteractionType::Synchronous_Messages)
+      .iter()
+      .filter_map(|i| i.as_v4_sync_message())
+      .filter(|message| message.description == description)
+      .map(|message| (message, pact.clone()))
+      .collect::<Vec<_>>())
+    .collect::<Vec<_>>();
+  if candidates.is_empty() {
+    return SyncMessageMatch::NotFound;
+  }
+
+  let mut mismatched = vec![];
+  for (message, pact) in candidates {
+    let actual = SynchronousMessage { request: contents.clone(), .. SynchronousMessage::default() };
+    let mismatches = pact_matching::match_sync_message_request(&message, &actual, &pact.boxed()).await;
+    if mismatches.is_empty() {
+      return SyncMessageMatch::Matched(Box::new(message));
+    }
+    mismatched.push((message, mismatches));
+  }
+  SyncMessageMatch::Mismatched(mismatched)
+}
+
+/// Response for the first response message of the interaction with its generators applied, or
+/// an empty response if the interaction has no response messages
+pub async fn sync_message_response(message: &SynchronousMessage) -> HttpResponse {
+  let (_, responses) = apply_generators_to_sync_message(message, &GeneratorTestMode::Provider,
+    &hashmap!{}, &vec![], &hashmap!{}).await;
+  if responses.len() > 1 {
+    warn!("Message '{}' has {} response messages, using the first one", message.description, responses.len());
+  }
+  match responses.first() {
+    Some(response) => message_response(response),
+    None => HttpResponse { status: 204, .. HttpResponse::default() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::generators;
+  use pact_models::generators::Generator;
+  use pact_models::content_types::{JSON, TEXT};
+  use pact_models::prelude::ProviderState;
+  use pact_models::v4::interaction::V4Interaction;
+
+  use super::*;
+
+  fn message(description: &str, state: &str) -> AsynchronousMessage {
+    AsynchronousMessage {
+      description: description.to_string(),
+      provider_states: vec![ProviderState::default(state)],
+      contents: MessageContents {
+        contents: OptionalBody::Present(json!({ "id": 1 }).to_string().into(), None, None),
+        metadata: hashmap!{ "contentType".to_string() => json!("application/json") },
+        generators: generators!{ "BODY" => { "$.id" => Generator::RandomInt(100, 200) } },
+        .. MessageContents::default()
+      },
+      .. AsynchronousMessage::default()
+    }
+  }
+
+  #[test]
+  fn selects_messages_by_description_and_provider_state() {
+    let pact = V4Pact {
+      interactions: vec![
+        message("order created", "an order").boxed_v4(),
+        message("order cancelled", "an order").boxed_v4(),
+        SynchronousHttp { description: "order created".to_string(), .. SynchronousHttp::default() }.boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let sources = vec![(pact, PactSource::Unknown)];
+    let select = |description: Option<&str>, state: Option<&str>| find_message(&sources, &MessageSelector {
+      description: description.map(|d| d.to_string()),
+      provider_state: state.map(|s| s.to_string())
+    }).map(|message| message.description);
+
+    expect!(select(Some("order cancelled"), None)).to(be_some().value("order cancelled"));
+    expect!(select(None, Some("an order"))).to(be_some().value("order created"));
+    expect!(select(Some("order cancelled"), Some("no orders"))).to(be_none());
+    expect!(MessageSelector::from_json(&json!({}))).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn matches_requests_against_synchronous_messages() {
+    let message = |name: &str, reply: &str| SynchronousMessage {
+      description: "get user".to_string(),
+      request: MessageContents {
+        contents: OptionalBody::Present(json!({ "name": name }).to_string().into(), Some(JSON.clone()), None),
+        .. MessageContents::default()
+      },
+      response: vec![MessageContents {
+        contents: OptionalBody::Present(reply.to_string().into(), Some(TEXT.clone()), None),
+        .. MessageContents::default()
+      }],
+      .. SynchronousMessage::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ message("alice", "user 1").boxed_v4(), message("bob", "user 2").boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let sources = vec![(pact, PactSource::Unknown)];
+    let request = |name: &str| request_contents(&HttpRequest {
+      method: "POST".to_string(),
+      headers: Some(hashmap!{ "Content-Type".to_string() => vec!["application/json".to_string()] }),
+      body: OptionalBody::Present(json!({ "name": name }).to_string().into(), None, None),
+      .. HttpRequest::default()
+    }).unwrap();
+
+    let SyncMessageMatch::Matched(matched) = match_sync_message(&sources, "get user", &request("bob")).await else {
+      panic!("Expected the request to match");
+    };
+    let response = sync_message_response(&matched).await;
+    expect!(response.body.value()).to(be_some().value("user 2".as_bytes()));
+
+    let result = match_sync_message(&sources, "get user", &request("carol")).await;
+    expect!(matches!(result, SyncMessageMatch::Mismatched(ref candidates) if candidates.len() == 2)).to(be_true());
+    let result = match_sync_message(&sources, "get order", &request("bob")).await;
+    expect!(matches!(result, SyncMessageMatch::NotFound)).to(be_true());
+  }
+
+  #[test]
+  fn reads_the_request_metadata_from_the_header() {
+    let request = HttpRequest {
+      headers: Some(hashmap!{ METADATA_HEADER.to_string() => vec![r#"{"key":"value"}"#.to_string()] }),
+      .. HttpRequest::default()
+    };
+    expect!(request_contents(&request).unwrap().metadata).to(be_equal_to(hashmap!{ "key".to_string() => json!("value") }));
+    let request = HttpRequest {
+      headers: Some(hashmap!{ METADATA_HEADER.to_string() => vec!["value".to_string()] }),
+      .. HttpRequest::default()
+    };
+    expect!(request_contents(&request)).to(be_err());
+  }
+
+  #[tokio::test]
+  async fn returns_the_generated_message_contents() {
+    let contents = generate_contents(&message("order created", "an order")).await;
+    let response = message_response(&contents);
+    let body: Value = serde_json::from_slice(&response.body.value().unwrap()).unwrap();
+    let id = body["id"].as_i64().unwrap();
+    expect!((100..=200).contains(&id)).to(be_true());
+
+    let headers = response.headers.unwrap();
+    expect!(headers.get("Content-Type").cloned()).to(be_some().value(vec!["application/json".to_string()]));
+    expect!(headers.get(METADATA_HEADER).cloned()).to(be_some().value(vec![r#"{"contentType":"application/json"}"#.to_string()]));
+  }
+}
@@ -36,8 +36,9 @@ fn extract_headers(headers: &HeaderMap<HeaderValue>) -> Option<HashMap<String, V
               warn!("Failed to parse HTTP header value: {}", err);
             })
           ).collect();
-        (name.as_str().into(), parsed_vals.iter().cloned()
-          .filter(|val| val.is_ok())
+        (name.as_str().into(), parsed_vals.iter()
+          .filter(|&val| val.is_ok())
+          .cloned()
           .map(|val| val.unwrap_or_default())
           .collect())
       })
@@ -90,12 +91,10 @@ pub fn pact_response_to_hyper_response(response: &HttpResponse) -> Result<HyperR
       let body_bytes = Bytes::copy_from_slice(body);
       let box_body = http_body_util::Full::from(body_bytes).boxed();
       res.body(box_body)
-          .map_err(|e| Error::from(e))
       },
     _ => {
       let box_body = http_body_util::Full::from(Bytes::new()).boxed();
       res.body(box_body)
-      .map_err(|e| Error::from(e))
     }
   }
 }
@@ -0,0 +1,210 @@
+//! Support for interactions that use transports provided by Pact plugins, such as gRPC. The
+//! plugins referenced in the pact metadata are loaded, and a mock server from the plugin is
+//! started for each transport, alongside the HTTP listener.
+
+use std::collections::BTreeSet;
+
+use anyhow::anyhow;
+use itertools::Itertools;
+use pact_models::pact::Pact;
+use pact_models::prelude::v4::V4Pact;
+use pact_plugin_driver::catalogue_manager;
+use pact_plugin_driver::mock_server::{MockServerConfig, MockServerDetails};
+use pact_plugin_driver::plugin_manager;
+use pact_plugin_driver::plugin_models::{PluginDependency, PluginDependencyType};
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::PactSource;
+
+/// Transports that are served by the HTTP listener
+const HTTP_TRANSPORTS: [&str; 2] = ["http", "https"];
+
+/// Mock server started by a plugin for the interactions of a pact that use its transport
+#[derive(Debug, Clone)]
+pub struct PluginMockServer {
+  /// Transport the mock server provides
+  pub transport: String,
+  /// Consumer and provider of the pact being served
+  pub pact: (String, String),
+  /// Plugin that started the mock server
+  pub plugin: PluginDependency,
+  /// Key the plugin uses for the mock server
+  pub key: String,
+  /// Port the mock server is running on
+  pub port: u32,
+  /// Base URL of the mock server
+  pub url: String
+}
+
+impl PluginMockServer {
+  fn new(transport: String, pact: &V4Pact, details: MockServerDetails) -> Self {
+    PluginMockServer {
+      transport,
+      pact: (pact.consumer.name.clone(), pact.provider.name.clone()),
+      plugin: details.plugin.manifest.as_dependency(),
+      key: details.key,
+      port: details.port,
+      url: details.base_url
+    }
+  }
+
+  /// Converts the mock server details to a JSON value
+  pub fn to_json(&self) -> Value {
+    json!({
+      "transport": self.transport,
+      "plugin": self.plugin.name,
+      "consumer": self.pact.0,
+      "provider": self.pact.1,
+      "port": self.port,
+      "url": self.url
+    })
+  }
+
+  async fn shutdown(&self) -> anyhow::Result<()> {
+    let plugin = plugin_manager::lookup_plugin(&self.plugin)
+      .ok_or_else(|| anyhow!("Plugin {} is not running", self.plugin))?;
+    let details = MockServerDetails {
+      key: self.key.clone(),
+      base_url: self.url.clone(),
+      port: self.port,
+      plugin
+    };
+    plugin_manager::shutdown_mock_server(&details).await.map(|_| ())
+  }
+}
+
+/// Transports of the interactions in the pact that are not served over HTTP
+pub fn plugin_transports(pact: &V4Pact) -> BTreeSet<String> {
+  pact.interactions.iter()
+    .filter_map(|interaction| interaction.transport())
+    .filter(|transport| !HTTP_TRANSPORTS.contains(&transport.to_lowercase().as_str()))
+    .collect()
+}
+
+/// Warning for a pact with interactions that use plugin transports, which are only served for
+/// the pacts loaded when the command line server starts. The other interactions are still served.
+pub fn plugin_transport_warning(pact: &V4Pact) -> Option<String> {
+  let transports = plugin_transports(pact);
+  if transports.is_empty() {
+    None
+  } else {
+    Some(format!("The pact between {} and {} uses the {} transport(s), which are provided by plugins and only \
+      served for the pacts loaded when the pact-stub-server command starts. Interactions using them will not be served",
+      pact.consumer.name, pact.provider.name, transports.iter().join(", ")))
+  }
+}
+
+/// Copy of the pact with only the interactions that use the transport
+fn pact_for_transport(pact: &V4Pact, transport: &str) -> V4Pact {
+  V4Pact {
+    interactions: pact.interactions.iter()
+      .filter(|interaction| interaction.transport().as_deref() == Some(transport))
+      .map(|interaction| interaction.boxed_v4())
+      .collect(),
+    .. pact.clone()
+  }
+}
+
+/// Loads the plugins required by the pacts, and starts a mock server for each transport provided
+/// by a plugin. Failures are logged, and the interactions for that transport are not served.
+pub async fn start_mock_servers(sources: &[(V4Pact, PactSource)], host: Option<String>) -> Vec<PluginMockServer> {
+  let mut servers = vec![];
+  for (pact, _) in sources.iter().filter(|(pact, _)| pact.requires_plugins() || !plugin_transports(pact).is_empty()) {
+    for plugin in pact.plugin_data().iter().unique_by(|plugin| (plugin.name.clone(), plugin.version.clone())) {
+      let dependency = PluginDependency {
+        name: plugin.name.clone(),
+        version: Some(plugin.version.clone()),
+        dependency_type: PluginDependencyType::Plugin
+      };
+      if let Err(err) = plugin_manager::load_plugin(&dependency).await {
+        error!("Failed to load plugin {}: {:#}", dependency, err);
+      }
+    }
+
+    for transport in plugin_transports(pact) {
+      let Some(entry) = catalogue_manager::lookup_entry(&format!("transport/{}", transport)) else {
+        warn!("No plugin provides the '{}' transport, interactions using it will not be served", transport);
+        continue;
+      };
+      let config = MockServerConfig { host_interface: host.clone(), .. MockServerConfig::default() };
+      let transport_pact = pact_for_transport(pact, &transport);
+      match plugin_manager::start_mock_server_v2(&entry, transport_pact.boxed(), config, Default::default()).await {
+        Ok(details) => {
+          info!("Started {} mock server for the pact between {} and {} on port {}", transport,
+            pact.consumer.name, pact.provider.name, details.port);
+          servers.push(PluginMockServer::new(transport, pact, details));
+        }
+        Err(err) => error!("Failed to start the {} mock server for the pact between {} and {}: {:#}", transport,
+          pact.consumer.name, pact.provider.name, err)
+      }
+    }
+  }
+  servers
+}
+
+/// Shuts down the plugin mock servers and the plugin processes. This needs to be called for any
+/// pacts that require plugins, even if none of the mock servers started, as the plugins are
+/// loaded regardless.
+pub async fn shutdown_mock_servers(servers: &[PluginMockServer]) {
+  for server in servers {
+    if let Err(err) = server.shutdown().await {
+      warn!("Failed to shut down the {} mock server: {:#}", server.transport, err);
+    }
+  }
+  plugin_manager::shutdown_plugins();
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::v4::interaction::V4Interaction;
+  use pact_models::v4::sync_message::SynchronousMessage;
+
+  use super::*;
+
+  #[test]
+  fn only_interactions_with_plugin_transports_are_served_by_plugins() {
+    let interaction = |description: &str, transport: Option<&str>| SynchronousMessage {
+      description: description.to_string(),
+      transport: transport.map(|t| t.to_string()),
+      .. SynchronousMessage::default()
+    }.boxed_v4();
+    let pact = V4Pact {
+      interactions: vec![
+        interaction("get user", Some("grpc")),
+        interaction("get order", Some("HTTP")),
+        interaction("order created", None),
+        interaction("get account", Some("grpc"))
+      ],
+      .. V4Pact::default()
+    };
+
+    expect!(plugin_transports(&pact).into_iter().collect::<Vec<_>>()).to(be_equal_to(vec!["grpc".to_string()]));
+    let descriptions = pact_for_transport(&pact, "grpc").interactions.iter()
+      .map(|i| i.description())
+      .collect::<Vec<_>>();
+    expect!(descriptions).to(be_equal_to(vec!["get user".to_string(), "get account".to_string()]));
+    expect!(plugin_transport_warning(&pact)).to(be_some());
+    expect!(plugin_transport_warning(&pact_for_transport(&pact, "HTTP"))).to(be_none());
+  }
+
+  #[tokio::test]
+  async fn transports_without_a_plugin_are_not_served() {
+    let pact = V4Pact {
+      interactions: vec![
+        SynchronousMessage {
+          description: "get user".to_string(),
+          transport: Some("unknown-transport".to_string()),
+          .. SynchronousMessage::default()
+        }.boxed_v4()
+      ],
+      .. V4Pact::default()
+    };
+    let sources = vec![(pact, PactSource::Uploaded("1".to_string()))];
+
+    let servers = start_mock_servers(&sources, None).await;
+    expect!(servers.is_empty()).to(be_true());
+    shutdown_mock_servers(&servers).await;
+  }
+}
@@ -0,0 +1,126 @@
+//! Forwarding of unmatched requests to an upstream server
+
+use std::convert::Infallible;
+
+use anyhow::Context;
+use futures::{future, StreamExt};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::Response;
+use pact_models::query_strings::build_query_string;
+use pact_models::v4::http_parts::HttpRequest;
+use reqwest::Url;
+use tracing::{info, warn};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, Infallible>;
+
+/// Headers that only apply to a single connection, so are not forwarded
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+  "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade"
+];
+
+/// Request headers that the HTTP client sets for the upstream request
+const CLIENT_HEADERS: [&str; 2] = ["host", "content-length"];
+
+/// Forwards requests to an upstream server
+#[derive(Debug, Clone)]
+pub struct Proxy {
+  client: reqwest::Client,
+  base_url: Url
+}
+
+impl Proxy {
+  /// Creates a proxy that forwards requests to the base URL. Redirects are not followed, so they
+  /// are returned to the client.
+  pub fn new(base_url: Url) -> anyhow::Result<Self> {
+    let client = reqwest::Client::builder()
+      .redirect(reqwest::redirect::Policy::none())
+      .build()
+      .context("Failed to create the HTTP client for the proxy")?;
+    Ok(Proxy { client, base_url })
+  }
+
+  /// Upstream URL for the request, with the request path appended to the base URL
+  pub fn url_for(&self, request: &HttpRequest) -> String {
+    let mut url = format!("{}{}", self.base_url.as_str().trim_end_matches('/'), request.path);
+    if let Some(query) = &request.query && !query.is_empty() {
+      url.push('?');
+      url.push_str(&build_query_string(query.clone()));
+    }
+    url
+  }
+
+  /// Sends the request to the upstream server, preserving the method, path, query, headers
+  /// and body
+  pub async fn forward(&self, request: &HttpRequest) -> anyhow::Result<reqwest::Response> {
+    let url = self.url_for(request);
+    info!("---> Proxying {} {} to {}", request.method.to_uppercase(), request.path, url);
+    let method = reqwest::Method::from_bytes(request.method.to_uppercase().as_bytes())
+      .with_context(|| format!("'{}' is not a valid HTTP method", request.method))?;
+    let mut builder = self.client.request(method, &url);
+    if let Some(headers) = &request.headers {
+      for (name, values) in headers {
+        if is_forwarded_header(name) {
+          for value in values {
+            builder = builder.header(name.as_str(), value.as_str());
+          }
+        }
+      }
+    }
+    if let Some(body) = request.body.value() {
+      builder = builder.body(body);
+    }
+    let response = builder.send().await
+      .with_context(|| format!("Failed to proxy request to {}", url))?;
+    info!("<--- Proxied response {} from {}", response.status(), url);
+    Ok(response)
+  }
+}
+
+/// If the header is passed on to the upstream server, rather than being specific to the
+/// connection or set by the HTTP client
+pub fn is_forwarded_header(name: &str) -> bool {
+  let name = name.to_lowercase();
+  !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !CLIENT_HEADERS.contains(&name.as_str())
+}
+
+/// Converts the upstream response to one that streams the body back to the client
+pub fn streamed_response(response: reqwest::Response) -> Result<Response<BoxBody>, http::Error> {
+  let mut builder = Response::builder().status(response.status().as_u16());
+  for (name, value) in response.headers() {
+    if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+      builder = builder.header(name.as_str(), value.as_bytes());
+    }
+  }
+
+  let url = response.url().to_string();
+  let stream = response.bytes_stream()
+    .take_while(move |chunk| {
+      if let Err(err) = chunk {
+        warn!("Failed to read the proxied response body from {}: {}", url, err);
+      }
+      future::ready(chunk.is_ok())
+    })
+    .filter_map(|chunk| future::ready(chunk.ok().map(|bytes| Ok::<_, Infallible>(Frame::data(bytes)))));
+  builder.body(BodyExt::boxed(StreamBody::new(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+
+  use super::*;
+
+  #[test]
+  fn appends_the_request_path_and_query_to_the_base_url() {
+    let proxy = Proxy::new(Url::parse("http://localhost:8080/api/").unwrap()).unwrap();
+    let request = HttpRequest {
+      path: "/users".to_string(),
+      query: Some(hashmap!{ "page".to_string() => vec![Some("2".to_string())] }),
+      .. HttpRequest::default()
+    };
+    expect!(proxy.url_for(&request)).to(be_equal_to("http://localhost:8080/api/users?page=2"));
+    expect!(proxy.url_for(&HttpRequest::default())).to(be_equal_to("http://localhost:8080/api/"));
+  }
+}
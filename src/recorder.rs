@@ -0,0 +1,232 @@
+//! Recording of proxied requests as interactions in a pact file
+
+use std::collections::HashMap;
+use std::panic::RefUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::ContentType;
+use pact_models::pact::{Pact, write_pact};
+use pact_models::PactSpecification;
+use pact_models::prelude::{Consumer, Provider};
+use pact_models::prelude::v4::{SynchronousHttp, V4Pact};
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::interaction::V4Interaction;
+use reqwest::Url;
+use tracing::{error, info};
+
+use crate::admin::request_signature;
+use crate::proxy::{self, Proxy};
+
+/// Request headers that describe the client or the transport rather than the request, so are
+/// not recorded. Headers starting with `sec-` are also excluded.
+const VOLATILE_REQUEST_HEADERS: [&str; 12] = [
+  "accept", "accept-encoding", "accept-language", "cache-control", "content-length", "cookie", "dnt",
+  "origin", "pragma", "referer", "user-agent", "x-forwarded-for"
+];
+
+/// Options for recording requests that do not match any interaction
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+  /// Pact file to merge the recorded interactions into
+  pub pact_file: PathBuf,
+  /// URL of the provider to forward requests to
+  pub upstream: Url,
+  /// Consumer name for the recorded pact
+  pub consumer: String,
+  /// Provider name for the recorded pact
+  pub provider: String
+}
+
+/// Forwards requests to the upstream provider and records each exchange in the pact file
+#[derive(Debug)]
+pub struct Recorder {
+  proxy: Proxy,
+  options: RecordOptions,
+  /// Interactions recorded by this server, which are served for the rest of the run
+  recorded: Mutex<Vec<SynchronousHttp>>
+}
+
+impl Recorder {
+  /// Creates a recorder for the options
+  pub fn new(options: RecordOptions) -> anyhow::Result<Self> {
+    let proxy = Proxy::new(options.upstream.clone())?;
+    Ok(Recorder { proxy, options, recorded: Mutex::new(vec![]) })
+  }
+
+  /// Pact with the interactions recorded so far, if any
+  pub fn recorded_pact(&self) -> Option<V4Pact> {
+    let recorded = self.recorded.lock().unwrap();
+    if recorded.is_empty() {
+      None
+    } else {
+      Some(self.pact(recorded.iter().map(|interaction| interaction.boxed_v4()).collect()))
+    }
+  }
+
+  /// Pact file the interactions are recorded in
+  pub fn pact_file(&self) -> &PathBuf {
+    &self.options.pact_file
+  }
+
+  fn pact(&self, interactions: Vec<Box<dyn V4Interaction + Send + Sync + RefUnwindSafe>>) -> V4Pact {
+    V4Pact {
+      consumer: Consumer { name: self.options.consumer.clone() },
+      provider: Provider { name: self.options.provider.clone() },
+      interactions,
+      .. V4Pact::default()
+    }
+  }
+
+  /// Forwards the request to the upstream provider and records the exchange. Failing to write
+  /// the pact file is logged, and the upstream response is still returned.
+  pub async fn forward(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+    let upstream_response = self.proxy.forward(request).await?;
+    let response = to_pact_response(upstream_response).await?;
+
+    let interaction = recorded_interaction(request, &response);
+    if let Err(err) = self.write(interaction.clone()).await {
+      error!("Failed to record interaction in {}: {:#}", self.options.pact_file.display(), err);
+    }
+    let mut recorded = self.recorded.lock().unwrap();
+    recorded.retain(|existing| existing.description != interaction.description);
+    recorded.push(interaction);
+    Ok(response)
+  }
+
+  async fn write(&self, interaction: SynchronousHttp) -> anyhow::Result<()> {
+    let description = interaction.description.clone();
+    let pact = self.pact(vec![ interaction.boxed_v4() ]);
+    let path = self.options.pact_file.clone();
+    tokio::task::spawn_blocking(move || write_pact(pact.boxed(), &path, PactSpecification::V4, false)).await??;
+    info!("Recorded interaction '{}' in {}", description, self.options.pact_file.display());
+    Ok(())
+  }
+}
+
+/// Builds the interaction for a recorded exchange. Its description is the request signature,
+/// so recording the same request again replaces the previous interaction.
+fn recorded_interaction(request: &HttpRequest, response: &HttpResponse) -> SynchronousHttp {
+  let request = HttpRequest {
+    headers: request.headers.as_ref().map(|headers| headers.iter()
+      .filter(|(name, _)| proxy::is_forwarded_header(name) && !is_volatile_header(name))
+      .map(|(name, values)| (name.clone(), values.clone()))
+      .collect()),
+    body: with_content_type(&request.body, request.headers.as_ref()),
+    .. request.clone()
+  };
+  SynchronousHttp {
+    description: request_signature(&request),
+    request,
+    response: response.clone(),
+    .. SynchronousHttp::default()
+  }
+}
+
+fn is_volatile_header(name: &str) -> bool {
+  let name = name.to_lowercase();
+  VOLATILE_REQUEST_HEADERS.contains(&name.as_str()) || name.starts_with("sec-")
+}
+
+async fn to_pact_response(response: reqwest::Response) -> anyhow::Result<HttpResponse> {
+  let status = response.status().as_u16();
+  let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+  for (name, value) in response.headers() {
+    if proxy::is_forwarded_header(name.as_str()) && name != http::header::DATE {
+      headers.entry(name.to_string()).or_default().push(value.to_str().unwrap_or_default().to_string());
+    }
+  }
+  let body = response.bytes().await.context("Failed to read the upstream response body")?;
+  let body = if body.is_empty() {
+    OptionalBody::Missing
+  } else {
+    OptionalBody::Present(body, None, None)
+  };
+  Ok(HttpResponse {
+    status,
+    body: with_content_type(&body, Some(&headers)),
+    headers: if headers.is_empty() { None } else { Some(headers) },
+    .. HttpResponse::default()
+  })
+}
+
+/// Sets the content type of the body from the headers. Empty bodies are treated as missing, so
+/// the recorded interaction does not require an empty body.
+fn with_content_type(body: &OptionalBody, headers: Option<&HashMap<String, Vec<String>>>) -> OptionalBody {
+  match body {
+    OptionalBody::Empty => OptionalBody::Missing,
+    OptionalBody::Present(bytes, None, encoding) => {
+      let content_type = headers.and_then(|headers| headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, values)| values.first())
+        .and_then(|value| ContentType::parse(value.as_str()).ok()));
+      OptionalBody::Present(bytes.clone(), content_type, *encoding)
+    }
+    _ => body.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::pact::read_pact;
+
+  use super::*;
+
+  #[test]
+  fn recorded_interactions_exclude_connection_and_client_headers() {
+    let request = HttpRequest {
+      method: "POST".to_string(),
+      path: "/orders".to_string(),
+      headers: Some(hashmap!{
+        "host".to_string() => vec!["localhost:1234".to_string()],
+        "user-agent".to_string() => vec!["curl/8.5.0".to_string()],
+        "Accept".to_string() => vec!["*/*".to_string()],
+        "accept-encoding".to_string() => vec!["gzip, br".to_string()],
+        "sec-fetch-mode".to_string() => vec!["cors".to_string()],
+        "authorization".to_string() => vec!["Bearer 1234".to_string()],
+        "content-type".to_string() => vec!["application/json".to_string()]
+      }),
+      body: OptionalBody::Present("{\"id\":1}".into(), None, None),
+      .. HttpRequest::default()
+    };
+    let response = HttpResponse { status: 201, .. HttpResponse::default() };
+
+    let interaction = recorded_interaction(&request, &response);
+    expect!(interaction.description.clone()).to(be_equal_to("POST /orders"));
+    expect!(interaction.request.headers.clone()).to(be_some().value(hashmap!{
+      "authorization".to_string() => vec!["Bearer 1234".to_string()],
+      "content-type".to_string() => vec!["application/json".to_string()]
+    }));
+    expect!(interaction.request.body.content_type().map(|ct| ct.to_string())).to(be_some().value("application/json"));
+    expect!(interaction.response.status).to(be_equal_to(201));
+  }
+
+  #[tokio::test]
+  async fn merges_recorded_interactions_into_the_pact_file() {
+    let pact_file = std::env::temp_dir().join(format!("pact-stub-server-record-{}.json", std::process::id()));
+    let recorder = Recorder::new(RecordOptions {
+      pact_file: pact_file.clone(),
+      upstream: Url::parse("http://localhost:1234").unwrap(),
+      consumer: "recording-consumer".to_string(),
+      provider: "recording-provider".to_string()
+    }).unwrap();
+    let response = HttpResponse { status: 200, .. HttpResponse::default() };
+    let users = HttpRequest { path: "/users".to_string(), .. HttpRequest::default() };
+    let orders = HttpRequest { path: "/orders".to_string(), .. HttpRequest::default() };
+
+    recorder.write(recorded_interaction(&users, &response)).await.unwrap();
+    recorder.write(recorded_interaction(&orders, &response)).await.unwrap();
+    recorder.write(recorded_interaction(&users, &response)).await.unwrap();
+
+    let pact = read_pact(&pact_file).unwrap();
+    std::fs::remove_file(&pact_file).unwrap();
+    expect!(pact.consumer().name).to(be_equal_to("recording-consumer"));
+    let mut descriptions = pact.interactions().iter().map(|i| i.description()).collect::<Vec<_>>();
+    descriptions.sort();
+    expect!(descriptions).to(be_equal_to(vec!["GET /orders".to_string(), "GET /users".to_string()]));
+  }
+}
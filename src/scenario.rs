@@ -0,0 +1,111 @@
+//! Stateful scenarios, where matched interactions can change the provider state used to select
+//! the interactions for subsequent requests
+
+use std::sync::Mutex;
+
+use pact_models::prelude::v4::SynchronousHttp;
+use regex::Regex;
+use tracing::info;
+
+/// Key in the interaction comments holding the state to move to once the interaction is matched
+pub const NEXT_STATE_KEY: &str = "nextState";
+
+/// Current state of a scenario
+#[derive(Debug)]
+pub struct Scenario {
+  initial_state: String,
+  current_state: Mutex<String>
+}
+
+impl Scenario {
+  /// Creates a scenario that starts in the given state
+  pub fn new<S: Into<String>>(initial_state: S) -> Self {
+    let initial_state = initial_state.into();
+    Scenario {
+      current_state: Mutex::new(initial_state.clone()),
+      initial_state
+    }
+  }
+
+  /// State the scenario started in
+  pub fn initial_state(&self) -> &str {
+    &self.initial_state
+  }
+
+  /// State the scenario is currently in
+  pub fn current_state(&self) -> String {
+    self.current_state.lock().unwrap().clone()
+  }
+
+  /// Provider state filter that only matches the current state exactly
+  pub fn state_filter(&self) -> Regex {
+    literal_regex(&self.current_state())
+  }
+
+  /// Moves to the given state
+  pub fn set_state<S: Into<String>>(&self, state: S) {
+    let state = state.into();
+    let mut current_state = self.current_state.lock().unwrap();
+    if *current_state != state {
+      info!("Scenario state changed from '{}' to '{}'", current_state, state);
+      *current_state = state;
+    }
+  }
+
+  /// Moves back to the initial state
+  pub fn reset(&self) {
+    self.set_state(self.initial_state.clone());
+  }
+
+  /// Moves to the next state of the interaction, if it has one
+  pub fn transition(&self, interaction: &SynchronousHttp) {
+    if let Some(state) = next_state(interaction) {
+      self.set_state(state);
+    }
+  }
+}
+
+/// State to move to once the interaction has been matched, from the `nextState` comment
+pub fn next_state(interaction: &SynchronousHttp) -> Option<&str> {
+  interaction.comments.get(NEXT_STATE_KEY).and_then(|state| state.as_str())
+}
+
+/// Regex that only matches the given value exactly
+pub fn literal_regex(value: &str) -> Regex {
+  Regex::new(format!("^{}$", regex::escape(value)).as_str()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn transitions_to_the_next_state_of_the_interaction() {
+    let scenario = Scenario::new("no orders");
+    let create = SynchronousHttp {
+      comments: hashmap!{ NEXT_STATE_KEY.to_string() => json!("order created") },
+      .. SynchronousHttp::default()
+    };
+
+    scenario.transition(&SynchronousHttp::default());
+    expect!(scenario.current_state()).to(be_equal_to("no orders"));
+    scenario.transition(&create);
+    expect!(scenario.current_state()).to(be_equal_to("order created"));
+    expect!(scenario.state_filter().is_match("order created")).to(be_true());
+    expect!(scenario.state_filter().is_match("order created again")).to(be_false());
+
+    scenario.reset();
+    expect!(scenario.current_state()).to(be_equal_to("no orders"));
+  }
+
+  #[test]
+  fn literal_regex_escapes_the_value() {
+    let regex = literal_regex("user (1)");
+    expect!(regex.is_match("user (1)")).to(be_true());
+    expect!(regex.is_match("user 1")).to(be_false());
+  }
+}
@@ -1,27 +1,37 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
-use tokio::sync::broadcast;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
 
-use anyhow::anyhow;
-use futures::executor::block_on;
+use anyhow::{anyhow, Context as _};
 use futures::future::Future;
 use futures::stream::StreamExt;
 use futures::task::{Context, Poll};
-use http::{StatusCode};
+use http::{HeaderValue, StatusCode};
 use http_body_util::BodyExt;
 use hyper::{Request as HyperRequest};
 use hyper::body::{Bytes, Incoming};
 use itertools::Itertools;
 use maplit::hashmap;
 use pact_matching::{CoreMatchingContext, DiffConfig, Mismatch};
+use pact_models::content_types::detect_content_type_from_bytes;
 use pact_models::generators::GeneratorTestMode;
+use pact_models::http_parts::HttpPart;
+use pact_models::json_utils::json_to_string;
 use pact_models::prelude::*;
 use pact_models::prelude::v4::*;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::interaction::V4Interaction;
 use pact_models::v4::V4InteractionType;
 use regex::Regex;
+use reqwest::Url;
+use serde_json::json;
 use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
 use tower_http::trace::{DefaultMakeSpan, Trace, TraceLayer};
 use tower_service::Service;
@@ -30,28 +40,236 @@ use hyper::Response;
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto::Builder,
+    server::graceful::{GracefulShutdown, Watcher},
 };
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
 use tower::Layer;
 
 type BoxBody = http_body_util::combinators::BoxBody<Bytes, Infallible>;
-
-use crate::{pact_support, PactSource};
+pub type SharedSources = Arc<Mutex<Vec<(V4Pact, PactSource)>>>;
+
+use crate::{admin, events, listener, pact_support, websocket, PactSource};
+use crate::events::DEFAULT_EVENT_DELAY;
+use crate::journal::{DEFAULT_JOURNAL_SIZE, Journal, JournalEntry};
+use crate::proxy::{self, Proxy};
+use crate::recorder::{RecordOptions, Recorder};
+use crate::scenario::{literal_regex, Scenario};
+use crate::matching::{MatchConfig, MismatchPolicy};
+use crate::messages::MessageCallback;
+use crate::strategy::{MatchStrategy, SequenceMode, Selection, Selector};
+use crate::listener::{BindAddress, Listener, LocalAddress};
+use crate::tls::TlsConfig;
+use crate::websocket::MessagePush;
 
 #[derive(Clone)]
 pub struct ServerHandler {
-  shared: Arc<Shared>,
+  pub(crate) shared: Arc<Shared>,
 }
 
 pub struct Shared {
   sources: Vec<(V4Pact, PactSource)>,
-  shared_sources: Option<Arc<Mutex<Vec<(V4Pact, PactSource)>>>>,
+  pub(crate) shared_sources: Option<SharedSources>,
   reload_tx: Option<broadcast::Sender<()>>,
-  auto_cors: bool,
-  cors_referer: bool,
-  provider_state: Option<Regex>,
-  provider_state_header_name: Option<String>,
-  empty_provider_states: bool
+  pub(crate) options: ServerOptions,
+  pub(crate) upload_count: AtomicUsize,
+  pub(crate) journal: Option<Journal>,
+  proxy: Option<Proxy>,
+  recorder: Option<Recorder>,
+  pub(crate) message_callback: Option<MessageCallback>,
+  pub(crate) websocket: Option<MessagePush>,
+  pub(crate) scenario: Option<Scenario>,
+  /// Provider state regex to filter the responses by, which can be changed through the admin API
+  pub(crate) provider_state: Mutex<Option<Regex>>,
+  pub(crate) matching: MatchConfig,
+  /// Set when the server is shutting down, which ends the open event streams
+  pub(crate) stopping: watch::Sender<bool>
+}
+
+impl Shared {
+  /// Current set of pacts being served. Uses the shared sources if in watch mode or the admin
+  /// API is enabled, otherwise the static sources, followed by any interactions recorded so far.
+  pub(crate) fn current_sources(&self) -> Vec<(V4Pact, PactSource)> {
+    let mut sources = if let Some(shared_sources) = &self.shared_sources {
+      shared_sources.lock().unwrap().clone()
+    } else {
+      self.sources.clone()
+    };
+    if let Some(recorder) = &self.recorder && let Some(pact) = recorder.recorded_pact() {
+      sources.push((pact, PactSource::File(recorder.pact_file().display().to_string())));
+    }
+    sources
+  }
+}
+
+/// Options controlling how requests are matched and responded to
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+  /// Automatically respond to OPTIONS requests and return default CORS headers
+  pub auto_cors: bool,
+  /// Set the CORS Access-Control-Allow-Origin header to the Referer
+  pub cors_referer: bool,
+  /// Provider state regular expression to filter the responses by
+  pub provider_state: Option<Regex>,
+  /// Name of the header containing the provider state to use for a request
+  pub provider_state_header_name: Option<String>,
+  /// Include empty provider states when filtering with a provider state
+  pub empty_provider_states: bool,
+  /// Match the provider state header value exactly, instead of treating it as a regular expression
+  pub provider_state_header_literal: bool,
+  /// Provider state parameters to filter the responses by. Interactions are only used if one of
+  /// their provider states has all these parameters with the same values.
+  pub provider_state_params: HashMap<String, serde_json::Value>,
+  /// Name of the header containing a JSON object of provider state parameters to use for a
+  /// request, in addition to the configured parameters
+  pub provider_state_params_header_name: Option<String>,
+  /// Return a JSON body describing the closest interactions when no interaction matches
+  pub explain_mismatches: bool,
+  /// How to select the interaction when more than one matches a request
+  pub match_strategy: MatchStrategy,
+  /// Move through the matching interactions in sequence for repeated requests, instead of
+  /// using the match strategy
+  pub sequence: Option<SequenceMode>,
+  /// Header mismatches disqualify an interaction, instead of only affecting which one is preferred
+  pub strict_headers: bool,
+  /// Headers whose mismatches disqualify an interaction, in addition to `strict_headers`
+  pub strict_header_names: Vec<String>,
+  /// Methods whose request bodies must match the interaction, or `*` for all methods
+  /// (defaults to POST, PUT, PATCH and QUERY)
+  pub body_methods: Option<Vec<String>>,
+  /// Response returned when no interaction matches a request
+  pub unmatched_response: UnmatchedResponse,
+  /// Forward requests that do not match any interaction to this URL
+  pub proxy_url: Option<Url>,
+  /// Forward requests that do not match any interaction to a provider and record them in a
+  /// pact file
+  pub record: Option<RecordOptions>,
+  /// Initial state of the scenario. If set, interactions are selected by the current state of
+  /// the scenario, which matched interactions can change.
+  pub scenario: Option<String>,
+  /// URL to POST messages triggered through the admin API to. If not set, the triggered
+  /// message is returned.
+  pub message_callback_url: Option<Url>,
+  /// Path to accept WebSocket connections on, which asynchronous messages are pushed to
+  pub websocket_path: Option<String>,
+  /// Push the next asynchronous message to the WebSocket clients at this interval, in addition
+  /// to the messages triggered through the admin API
+  pub websocket_interval: Option<Duration>,
+  /// Delay between the events of Server-Sent Events responses (defaults to 1 second)
+  pub event_delay: Option<Duration>,
+  /// Path prefix for the admin API, which is disabled if not set
+  pub admin_prefix: Option<String>,
+  /// Maximum number of requests kept in the journal when the admin API is enabled
+  /// (defaults to 1000)
+  pub journal_size: Option<usize>
+}
+
+/// Shape of the response returned when no interaction matches a request
+#[derive(Debug, Clone)]
+pub struct UnmatchedResponse {
+  /// Status code to return
+  pub status: u16,
+  /// Headers to add to the response. Repeated names result in multiple values.
+  pub headers: Vec<(String, String)>,
+  /// Body to return. This replaces the body returned with `explain_mismatches`.
+  pub body: Option<Bytes>
+}
+
+impl Default for UnmatchedResponse {
+  fn default() -> Self {
+    UnmatchedResponse {
+      status: StatusCode::NOT_FOUND.as_u16(),
+      headers: vec![],
+      body: None
+    }
+  }
+}
+
+impl UnmatchedResponse {
+  fn to_response(&self) -> HttpResponse {
+    let mut headers: HashMap<String, Vec<String>> = hashmap!{};
+    for (name, value) in &self.headers {
+      headers.entry(name.clone()).or_default().push(value.clone());
+    }
+    HttpResponse {
+      status: self.status,
+      headers: if headers.is_empty() { None } else { Some(headers) },
+      body: match &self.body {
+        Some(body) => OptionalBody::Present(body.clone(), detect_content_type_from_bytes(body), None),
+        None => OptionalBody::Missing
+      },
+      .. HttpResponse::default()
+    }
+  }
+}
+
+fn proxy(options: &ServerOptions) -> Option<Proxy> {
+  options.proxy_url.as_ref().and_then(|url| match Proxy::new(url.clone()) {
+    Ok(proxy) => {
+      info!("Requests that do not match any interaction will be proxied to {}", url);
+      Some(proxy)
+    },
+    Err(err) => {
+      error!("{:#}, requests will not be proxied", err);
+      None
+    }
+  })
+}
+
+fn message_callback(options: &ServerOptions) -> Option<MessageCallback> {
+  options.message_callback_url.as_ref().and_then(|url| match MessageCallback::new(url.clone()) {
+    Ok(callback) => {
+      info!("Delivering triggered messages to {}", url);
+      Some(callback)
+    },
+    Err(err) => {
+      error!("{:#}, triggered messages will be returned instead", err);
+      None
+    }
+  })
+}
+
+fn websocket(options: &ServerOptions) -> Option<MessagePush> {
+  options.websocket_path.as_ref().map(|path| {
+    let push = MessagePush::new(path.as_str());
+    info!("Accepting WebSocket connections on {}", push.path());
+    push
+  })
+}
+
+fn match_config(options: &ServerOptions) -> MatchConfig {
+  let policy = MismatchPolicy::new(options.strict_headers, &options.strict_header_names);
+  MatchConfig {
+    policy: match &options.body_methods {
+      Some(methods) => policy.with_body_methods(methods),
+      None => policy
+    },
+    selector: match options.sequence {
+      Some(mode) => Selector::sequenced(mode),
+      None => Selector::new(options.match_strategy)
+    },
+    prefer_matching_states: options.scenario.is_some()
+  }
+}
+
+fn recorder(options: &ServerOptions) -> Option<Recorder> {
+  options.record.as_ref().and_then(|record| match Recorder::new(record.clone()) {
+    Ok(recorder) => {
+      info!("Recording requests that do not match any interaction from {} into {}", record.upstream,
+        record.pact_file.display());
+      Some(recorder)
+    },
+    Err(err) => {
+      error!("{:#}, requests will not be recorded", err);
+      None
+    }
+  })
+}
+
+/// Requests are only journaled when they can be queried through the admin API
+fn journal(options: &ServerOptions) -> Option<Journal> {
+  options.admin_prefix.as_ref()
+    .map(|_| Journal::new(options.journal_size.unwrap_or(DEFAULT_JOURNAL_SIZE)))
 }
 
 pub trait TraceLayerProvider {
@@ -75,111 +293,266 @@ impl<S> Layer<S> for ServerHandlerLayer {
 }
 
 impl ServerHandler {
-  pub fn new(
-    sources: Vec<(V4Pact, PactSource)>,
-    auto_cors: bool,
-    cors_referer: bool,
-    provider_state: Option<Regex>,
-    provider_state_header_name: Option<String>,
-    empty_provider_states: bool
-  ) -> ServerHandler {
+  pub fn new(sources: Vec<(V4Pact, PactSource)>, options: ServerOptions) -> ServerHandler {
+    // Pacts can be uploaded and removed through the admin API, so they need to be shared
+    let (sources, shared_sources) = if options.admin_prefix.is_some() {
+      (vec![], Some(Arc::new(Mutex::new(sources))))
+    } else {
+      (sources, None)
+    };
     ServerHandler {
       shared: Arc::new(Shared {
         sources,
-        shared_sources: None,
+        shared_sources,
         reload_tx: None,
-        auto_cors,
-        cors_referer,
-        provider_state,
-        provider_state_header_name,
-        empty_provider_states
+        journal: journal(&options),
+        proxy: proxy(&options),
+        recorder: recorder(&options),
+        message_callback: message_callback(&options),
+        websocket: websocket(&options),
+        scenario: options.scenario.as_ref().map(Scenario::new),
+        provider_state: Mutex::new(options.provider_state.clone()),
+        matching: match_config(&options),
+        options,
+        upload_count: AtomicUsize::new(0),
+        stopping: watch::Sender::new(false)
       })
     }
   }
   
   pub fn new_with_watch(
-    shared_sources: Arc<Mutex<Vec<(V4Pact, PactSource)>>>,
+    shared_sources: SharedSources,
     reload_tx: broadcast::Sender<()>,
-    auto_cors: bool,
-    cors_referer: bool,
-    provider_state: Option<Regex>,
-    provider_state_header_name: Option<String>,
-    empty_provider_states: bool
+    options: ServerOptions
   ) -> ServerHandler {
     ServerHandler {
       shared: Arc::new(Shared {
         sources: vec![], // Empty since we use shared_sources
         shared_sources: Some(shared_sources),
         reload_tx: Some(reload_tx),
-        auto_cors,
-        cors_referer,
-        provider_state,
-        provider_state_header_name,
-        empty_provider_states
+        journal: journal(&options),
+        proxy: proxy(&options),
+        recorder: recorder(&options),
+        message_callback: message_callback(&options),
+        websocket: websocket(&options),
+        scenario: options.scenario.as_ref().map(Scenario::new),
+        provider_state: Mutex::new(options.provider_state.clone()),
+        matching: match_config(&options),
+        options,
+        upload_count: AtomicUsize::new(0),
+        stopping: watch::Sender::new(false)
       })
     }
   }
 
-  pub fn start_server(self, port: u16) -> Result<(), ExitCode> {
-    let addr = std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(0, 0, 0, 0), port);
-    let addr = std::net::SocketAddr::V4(addr);
-
-    let handler = self.clone();
-    
+  /// Serves requests on the bound listener until the shutdown future resolves, then waits for
+  /// in-flight requests to complete.
+  pub async fn serve<F>(self, listener: BoundListener, shutdown: F) -> Result<(), ExitCode>
+    where F: Future<Output = ()> + Send {
     // Start reload listener if in watch mode
-    if let Some(reload_tx) = &handler.shared.reload_tx {
+    if let Some(reload_tx) = &self.shared.reload_tx {
       let mut reload_rx = reload_tx.subscribe();
       tokio::spawn(async move {
-        while let Ok(_) = reload_rx.recv().await {
+        while reload_rx.recv().await.is_ok() {
           info!("Pacts reloaded - server will use updated pacts for new requests");
         }
       });
     }
-    
-    block_on(async move {
-      let listener = match TcpListener::bind(addr).await {
-        Ok(l) => l,
-        Err(err) => {
-          error!("tcp listener failed to bind address: {}", err);
-          return Err(ExitCode::FAILURE);
+
+    let schedule = self.shared.options.websocket_interval
+      .filter(|_| self.shared.websocket.is_some())
+      .map(|interval| tokio::spawn(websocket::push_on_schedule(self.shared.clone(), interval)));
+
+    let BoundListener { socket, tls_acceptor, shutdown_timeout, .. } = listener;
+    let result = match socket {
+      BoundSocket::Tcp(listener) =>
+        accept_connections(self, listener, tls_acceptor, shutdown, shutdown_timeout).await,
+      #[cfg(unix)]
+      BoundSocket::Unix(listener, path) => {
+        let result = accept_connections(self, listener, tls_acceptor, shutdown, shutdown_timeout).await;
+        if let Err(err) = std::fs::remove_file(&path) {
+          warn!("Failed to remove socket file {}: {}", path.display(), err);
         }
-      };
+        result
+      }
+    };
+    if let Some(schedule) = schedule {
+      schedule.abort();
+    }
+    result
+  }
+}
+
+/// Listener that has been bound to its address but is not yet accepting connections
+pub struct BoundListener {
+  socket: BoundSocket,
+  address: LocalAddress,
+  tls_acceptor: Option<TlsAcceptor>,
+  shutdown_timeout: Duration
+}
+
+enum BoundSocket {
+  Tcp(tokio::net::TcpListener),
+  #[cfg(unix)]
+  Unix(tokio::net::UnixListener, std::path::PathBuf)
+}
+
+impl BoundListener {
+  /// Address the listener is bound to
+  pub fn local_address(&self) -> &LocalAddress {
+    &self.address
+  }
+}
+
+/// Binds a listener using the given options
+pub async fn bind(options: ListenOptions) -> anyhow::Result<BoundListener> {
+  let ListenOptions { host, port, tls, shutdown_timeout } = options;
+  let tls_acceptor = tls.map(|config| config.acceptor())
+    .transpose()
+    .context("failed to setup TLS")?;
+  let scheme = if tls_acceptor.is_some() { " (HTTPS)" } else { "" };
+
+  let (socket, address) = match host {
+    BindAddress::Ip(ip) => {
+      let listener = listener::bind_tcp(ip, port).await
+        .with_context(|| format!("tcp listener failed to bind address {}", SocketAddr::new(ip, port)))?;
+      let local_addr = listener.local_addr().context("failed to get local address")?;
+      info!("Server started on port {}{}", local_addr.port(), scheme);
+      debug!("Listening on {}", local_addr);
+      (BoundSocket::Tcp(listener), LocalAddress::Tcp(local_addr))
+    }
+    #[cfg(unix)]
+    BindAddress::Unix(path) => {
+      if port != 0 {
+        warn!("Ignoring port {} as the server is bound to a unix domain socket", port);
+      }
+      let listener = listener::bind_unix(&path)
+        .with_context(|| format!("unix listener failed to bind {}", path.display()))?;
+      info!("Server started on unix socket {}{}", path.display(), scheme);
+      (BoundSocket::Unix(listener, path.clone()), LocalAddress::Unix(path))
+    }
+  };
+
+  Ok(BoundListener { socket, address, tls_acceptor, shutdown_timeout })
+}
+
+/// Options controlling where and how the server accepts connections
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+  /// Address to bind to
+  pub host: BindAddress,
+  /// Port to bind to, 0 lets the OS assign one
+  pub port: u16,
+  /// Serve HTTPS with this configuration
+  pub tls: Option<TlsConfig>,
+  /// How long to wait for in-flight requests to complete when shutting down
+  pub shutdown_timeout: Duration
+}
+
+impl Default for ListenOptions {
+  fn default() -> Self {
+    ListenOptions {
+      host: BindAddress::default(),
+      port: 0,
+      tls: None,
+      shutdown_timeout: Duration::from_secs(10)
+    }
+  }
+}
+
+/// Resolves when the process receives SIGINT (Ctrl-C) or SIGTERM
+pub async fn shutdown_signal() {
+  let ctrl_c = async {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+      error!("Failed to listen for SIGINT: {}", err);
+      std::future::pending::<()>().await;
+    }
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut signal) => { signal.recv().await; },
+      Err(err) => {
+        error!("Failed to listen for SIGTERM: {}", err);
+        std::future::pending::<()>().await;
+      }
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = ctrl_c => info!("Received SIGINT"),
+    _ = terminate => info!("Received SIGTERM")
+  }
+}
 
-      let local_addr = match listener.local_addr() {
-        Ok(a) => a,
+async fn accept_connections<L, F>(
+  handler: ServerHandler,
+  listener: L,
+  tls_acceptor: Option<TlsAcceptor>,
+  shutdown: F,
+  shutdown_timeout: Duration
+) -> Result<(), ExitCode>
+  where L: Listener,
+        F: Future<Output = ()> + Send {
+  let graceful = GracefulShutdown::new();
+  let mut connections = JoinSet::new();
+  tokio::pin!(shutdown);
+
+  loop {
+    tokio::select! {
+      result = listener.accept() => match result {
+        Ok((stream, peer)) => {
+          let handler = handler.clone();
+          let tls_acceptor = tls_acceptor.clone();
+          let watcher = graceful.watcher();
+          connections.spawn(async move {
+            match tls_acceptor {
+              Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => serve_connection(handler, stream, watcher).await,
+                Err(err) => warn!("TLS handshake with {} failed: {}", peer, err)
+              },
+              None => serve_connection(handler, stream, watcher).await
+            }
+          });
+        }
         Err(err) => {
-          error!("failed to get local address: {}", err);
+          error!("listener failed to accept: {}", err);
           return Err(ExitCode::FAILURE);
         }
-      };
-      info!("Server started on port {}", local_addr.port());
-
-      loop {
-        match listener.accept().await {
-          Ok((stream, _)) => {
-            let handler = handler.clone();
-            tokio::spawn(async move {
-              let io = TokioIo::new(stream);
-              let handler = handler.clone();
-              let tower_service = tower::ServiceBuilder::new()
-                .layer(ServerHandlerLayer)
-                .service(handler);
-              let hyper_service = hyper_util::service::TowerToHyperService::new(tower_service);
-              if let Err(err) = Builder::new(TokioExecutor::new())
-                .serve_connection(io, hyper_service)
-                .await
-              {
-                error!("error serving connection: {:?}", err);
-              }
-            });
-          }
-          Err(err) => {
-            error!("tcp listener failed to accept: {}", err);
-            return Err(ExitCode::FAILURE);
-          }
-        }
-      }
-    })
+      },
+      Some(_) = connections.join_next(), if !connections.is_empty() => {},
+      _ = &mut shutdown => break
+    }
+  }
+
+  // Stop accepting new connections and let the in-flight ones finish
+  drop(listener);
+  handler.shared.stopping.send_replace(true);
+  info!("Shutting down, waiting up to {:?} for {} open connection(s) to complete", shutdown_timeout, graceful.count());
+  match tokio::time::timeout(shutdown_timeout, graceful.shutdown()).await {
+    Ok(()) => info!("All connections closed, server stopped"),
+    Err(_) => {
+      // Close the connections that are still open, so they do not outlive the server
+      connections.abort_all();
+      warn!("Timed out waiting for connections to close, server stopped")
+    }
+  }
+  Ok(())
+}
+
+async fn serve_connection<IO>(handler: ServerHandler, stream: IO, watcher: Watcher)
+  where IO: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+  let io = TokioIo::new(stream);
+  let tower_service = tower::ServiceBuilder::new()
+    .layer(ServerHandlerLayer)
+    .service(handler);
+  let hyper_service = hyper_util::service::TowerToHyperService::new(tower_service);
+  let builder = Builder::new(TokioExecutor::new());
+  if let Err(err) = watcher.watch(builder.serve_connection_with_upgrades(io, hyper_service)).await {
+    error!("error serving connection: {:?}", err);
   }
 }
 
@@ -194,32 +567,24 @@ impl Service<HyperRequest<Incoming>> for ServerHandler {
 
   fn call(&mut self, req: HyperRequest<Incoming>) -> Self::Future {
     let shared = self.shared.as_ref();
-    let auto_cors = shared.auto_cors;
-    let cors_referer = shared.cors_referer;
-    
-    // Use shared sources if in watch mode, otherwise use static sources
-    let sources = if let Some(shared_sources) = &shared.shared_sources {
-      shared_sources.lock().unwrap().clone()
-    } else {
-      shared.sources.clone()
-    };
-    
-    let provider_state = shared.provider_state.clone();
-    let provider_state_header_name = shared.provider_state_header_name.clone();
-    let empty_provider_states = shared.empty_provider_states;
+    if let Some(push) = shared.websocket.as_ref().filter(|push| push.is_upgrade(&req)) {
+      let response = push.upgrade(req, shared.stopping.subscribe());
+      return Box::pin(async move { pact_support::pact_response_to_hyper_response(&response) });
+    }
+    let provider_state = shared.provider_state.lock().unwrap().clone();
+    let provider_state_header_name = shared.options.provider_state_header_name.clone();
+    let literal_header = shared.options.provider_state_header_literal;
+    let admin_prefix = shared.options.admin_prefix.clone();
+    let shared = self.shared.clone();
 
     Box::pin(async move {
       let (parts, body) = req.into_parts();
       let provider_state = match provider_state_header_name {
-        Some(name) => {
-          let parts_value = &parts;
-          let provider_state_header = parts_value.headers.get(name);
-          match provider_state_header {
-            Some(header) => Some(Regex::new(header.to_str().unwrap()).unwrap()),
-            None => provider_state
-          }
+        Some(name) => match parts.headers.get(&name) {
+          Some(header) => provider_state_from_header(&name, header, literal_header).map(Some),
+          None => Ok(provider_state)
         },
-        None => provider_state
+        None => Ok(provider_state)
       };
 
       let bytes = body
@@ -241,15 +606,97 @@ impl Service<HyperRequest<Incoming>> for ServerHandler {
         }
       };
       let request = pact_support::hyper_request_to_pact_request(parts, body);
-      let response = handle_request(request, auto_cors, cors_referer,
-        sources, provider_state, empty_provider_states).await;
-      pact_support::pact_response_to_hyper_response(&response)
+      if let Some(path) = admin_prefix.as_ref().and_then(|prefix| admin::admin_path(prefix, &request.path)) {
+        let response = admin::handle_admin_request(&shared, &request, path).await;
+        return pact_support::pact_response_to_hyper_response(&response);
+      }
+      let state = provider_state.and_then(|provider_state| provider_state_params(&shared.options, &request)
+        .map(|params| (provider_state, params)));
+      let (provider_state, state_params) = match state {
+        Ok(state) => state,
+        Err(err) => {
+          warn!("{}, sending {}", err, StatusCode::BAD_REQUEST);
+          if let Some(journal) = &shared.journal {
+            journal.record(JournalEntry::rejected(&request, StatusCode::BAD_REQUEST.as_u16(), &err));
+          }
+          let response = admin::json_response(StatusCode::BAD_REQUEST.as_u16(), json!({ "error": err }));
+          return pact_support::pact_response_to_hyper_response(&response);
+        }
+      };
+      match handle_request(&shared, request, provider_state, &state_params).await {
+        Reply::Pact(response) => pact_support::pact_response_to_hyper_response(&response),
+        Reply::Proxied(response) => proxy::streamed_response(response),
+        Reply::Events(response, delay) => events::event_stream_response(&response, delay, shared.stopping.subscribe())
+      }
     })
   }
 }
 
-fn method_supports_payload(request: &HttpRequest) -> bool {
-  matches!(request.method.to_uppercase().as_str(), "POST" | "PUT" | "PATCH")
+/// Provider state filter from the provider state header. The header value is a regular expression,
+/// or the exact provider state name if `literal` is set.
+fn provider_state_from_header(name: &str, header: &HeaderValue, literal: bool) -> Result<Regex, String> {
+  let value = header.to_str()
+    .map_err(|_| format!("Provider state header '{}' contains characters that are not visible ASCII", name))?;
+  if literal {
+    Ok(literal_regex(value))
+  } else {
+    Regex::new(value)
+      .map_err(|err| format!("Provider state header '{}' is not a valid regular expression: {}", name, err))
+  }
+}
+
+/// Provider state parameters for the request. Parameters from the header override the configured
+/// ones with the same name.
+fn provider_state_params(
+  options: &ServerOptions,
+  request: &HttpRequest
+) -> Result<HashMap<String, serde_json::Value>, String> {
+  let mut params = options.provider_state_params.clone();
+  let header = options.provider_state_params_header_name.as_ref()
+    .and_then(|name| request.lookup_header_value(name).map(|value| (name, value)));
+  if let Some((name, value)) = header {
+    match serde_json::from_str::<serde_json::Value>(&value) {
+      Ok(serde_json::Value::Object(values)) => params.extend(values),
+      Ok(_) => return Err(format!("Provider state parameters header '{}' must be a JSON object", name)),
+      Err(err) => return Err(format!("Provider state parameters header '{}' is not valid JSON: {}", name, err))
+    }
+  }
+  Ok(params)
+}
+
+/// Removes the HTTP interactions that do not have a provider state with all the parameters.
+/// Interactions without provider states are kept if `empty_provider_states` is set.
+fn filter_by_state_params(
+  sources: Vec<(V4Pact, PactSource)>,
+  params: &HashMap<String, serde_json::Value>,
+  empty_provider_states: bool
+) -> Vec<(V4Pact, PactSource)> {
+  if params.is_empty() {
+    return sources;
+  }
+  sources.into_iter()
+    .map(|(mut pact, source)| {
+      pact.interactions.retain(|interaction| {
+        let states = interaction.provider_states();
+        !interaction.is_request_response() ||
+          empty_provider_states && states.is_empty() ||
+          states.iter().any(|state| params.iter().all(|(key, value)| {
+            state.params.get(key).map(|actual| json_to_string(actual) == json_to_string(value)).unwrap_or(false)
+          }))
+      });
+      (pact, source)
+    })
+    .collect()
+}
+
+/// Outcome of matching a request against the interactions being served
+pub(crate) struct MatchResult {
+  /// Interaction used to generate the response
+  pub interaction: Option<SynchronousHttp>,
+  /// Interactions with the same method and path as the request, with their mismatches
+  pub candidates: Vec<(SynchronousHttp, Vec<Mismatch>)>,
+  /// Response to return, or an error if no interaction matched
+  pub response: anyhow::Result<HttpResponse>
 }
 
 async fn find_matching_request(
@@ -258,11 +705,11 @@ async fn find_matching_request(
   cors_referer: bool,
   sources: Vec<(V4Pact, PactSource)>,
   provider_state: Option<Regex>,
-  empty_provider_states: bool
-) -> anyhow::Result<HttpResponse> {
-  match &provider_state {
-    Some(state) => info!("Filtering interactions by provider state regex '{}'", state),
-    None => ()
+  empty_provider_states: bool,
+  config: &MatchConfig
+) -> MatchResult {
+  if let Some(state) = &provider_state {
+    info!("Filtering interactions by provider state regex '{}'", state);
   }
 
   // Get a subset of all interactions across all pacts that match the method and path
@@ -292,46 +739,44 @@ async fn find_matching_request(
     });
 
   // Match all interactions from the sublist against the incoming request
-  let results = futures::stream::iter(interactions)
+  let candidates = futures::stream::iter(interactions)
     .filter_map(|(i, pact)| async move {
-      match pact_matching::match_request(i.request.clone(), request.clone(), &pact.boxed(), &i.boxed()).await {
-        Ok(result) => {
-          let mismatches = result.mismatches();
-          let all_matched = mismatches.iter().all(|mismatch|{
-            match mismatch {
-              Mismatch::MethodMismatch { .. } => false,
-              Mismatch::PathMismatch { .. } => false,
-              Mismatch::QueryMismatch { .. } => false,
-              Mismatch::BodyMismatch { .. } => !(method_supports_payload(request) && request.body.is_present()),
-              _ => true
-            }
-          });
-          if all_matched {
-            Some((i.clone(), mismatches.clone()))
-          } else {
-            None
-          }
-        }
-        Err(_) => None
-      }
+      pact_matching::match_request(i.request.clone(), request.clone(), &pact.boxed(), &i.boxed()).await
+        .ok()
+        .map(|result| (i, result.mismatches()))
     })
     .collect::<Vec<_>>()
     .await;
-
-  // Find the result with the least number of mismatches
-  let match_results = results.iter()
-    .sorted_by(|a, b| Ord::cmp(&a.1.len(), &b.1.len()))
+  let results = candidates.iter()
+    .filter(|(_, mismatches)| !mismatches.iter().any(|mismatch| config.policy.disqualifies(request, mismatch)));
+
+  // Order the results by the match strategy, and in scenario mode prefer interactions with a
+  // matching provider state over those included because their provider state is empty
+  let selector = &config.selector;
+  let strategy = selector.strategy();
+  let match_results = results
+    .sorted_by_key(|(i, mismatches)| {
+      let empty_state = config.prefer_matching_states && provider_state.is_some() &&
+        i.provider_states.iter().all(|state| state.name.is_empty());
+      (empty_state, strategy.rank(i, mismatches))
+    })
     .cloned()
     .collect::<Vec<(SynchronousHttp, Vec<Mismatch>)>>();
 
   if match_results.len() > 1 {
-    warn!("Found more than one pact request for method {} and path '{}', using the first one with the least number of mismatches",
-          request.method, request.path);
+    warn!("Found more than one pact request for method {} and path '{}', using {}",
+          request.method, request.path, selector);
   }
 
-  match match_results.first() {
-    Some((interaction, _)) => Ok(pact_matching::generate_response(&interaction.response,  &GeneratorTestMode::Provider, &hashmap!{}).await),
-    None => {
+  let selection = selector.select(request, &match_results);
+  let interaction = match &selection {
+    Selection::Selected(interaction) => Some(interaction.as_ref().clone()),
+    _ => None
+  };
+  let response = match &selection {
+    Selection::Selected(interaction) => Ok(pact_matching::generate_response(&interaction.response,  &GeneratorTestMode::Provider, &hashmap!{}).await),
+    Selection::Ambiguous(interactions) => Ok(ambiguous_match_response(request, interactions)),
+    Selection::None => {
       if auto_cors && request.method.to_uppercase() == "OPTIONS" {
         let origin = if cors_referer {
           match request.headers {
@@ -344,7 +789,7 @@ async fn find_matching_request(
         Ok(HttpResponse {
           headers: Some(hashmap!{
             "Access-Control-Allow-Headers".to_string() => vec!["*".to_string()],
-            "Access-Control-Allow-Methods".to_string() => vec!["GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH".to_string()],
+            "Access-Control-Allow-Methods".to_string() => vec!["GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH, QUERY".to_string()],
             "Access-Control-Allow-Origin".to_string() => vec![origin]
           }),
           .. HttpResponse::default()
@@ -353,35 +798,173 @@ async fn find_matching_request(
         Err(anyhow!("No matching request found for path {}", request.path))
       }
     }
-  }
+  };
+
+  MatchResult { interaction, candidates, response }
 }
 
-async fn handle_request(
+/// Conflict response returned by the `error` match strategy when more than one interaction
+/// matches the request
+fn ambiguous_match_response(request: &HttpRequest, interactions: &[SynchronousHttp]) -> HttpResponse {
+  warn!("Request matched {} interactions, sending {}", interactions.len(), StatusCode::CONFLICT);
+  admin::json_response(StatusCode::CONFLICT.as_u16(), json!({
+    "error": format!("Found {} interactions matching {}", interactions.len(), admin::request_signature(request)),
+    "candidates": interactions.iter().map(|interaction| json!({
+      "key": interaction.unique_key(),
+      "description": interaction.description,
+      "providerStates": interaction.provider_states.iter().map(|ps| ps.name.clone()).collect::<Vec<_>>()
+    })).collect::<Vec<_>>()
+  }))
+}
+
+/// Content type of the body returned with `--explain-mismatches` when no interaction matches
+const MISMATCH_CONTENT_TYPE: &str = "application/vnd.pact-stub-server.mismatches+json";
+
+/// Number of candidate interactions described when no interaction matches
+const CLOSEST_INTERACTIONS: usize = 3;
+
+/// Interactions closest to the request, with their mismatches. These are the candidates with the
+/// same method and path if there were any, otherwise every HTTP interaction is compared.
+async fn closest_interactions(
+  request: &HttpRequest,
+  sources: &[(V4Pact, PactSource)],
+  candidates: &[(SynchronousHttp, Vec<Mismatch>)]
+) -> Vec<(SynchronousHttp, Vec<Mismatch>)> {
+  let candidates = if candidates.is_empty() {
+    let interactions = sources.iter()
+      .flat_map(|(pact, _)| {
+        pact.filter_interactions(V4InteractionType::Synchronous_HTTP)
+          .iter()
+          .filter_map(|i| i.as_v4_http())
+          .map(|i| (i, pact.clone()))
+          .collect_vec()
+      });
+    futures::stream::iter(interactions)
+      .filter_map(|(i, pact)| async move {
+        pact_matching::match_request(i.request.clone(), request.clone(), &pact.boxed(), &i.boxed()).await
+          .ok()
+          .map(|result| (i, result.mismatches()))
+      })
+      .collect::<Vec<_>>()
+      .await
+  } else {
+    candidates.to_vec()
+  };
+  candidates.into_iter()
+    .sorted_by_key(|(_, mismatches)| mismatches.len())
+    .take(CLOSEST_INTERACTIONS)
+    .collect()
+}
+
+fn mismatch_json(
+  error: &anyhow::Error,
+  request: &HttpRequest,
+  closest: &[(SynchronousHttp, Vec<Mismatch>)]
+) -> serde_json::Value {
+  json!({
+    "error": error.to_string(),
+    "request": {
+      "method": request.method.to_uppercase(),
+      "path": request.path,
+      "query": request.query
+    },
+    "closestInteractions": closest.iter().map(|(interaction, mismatches)| json!({
+      "description": interaction.description,
+      "providerStates": interaction.provider_states.iter().map(|ps| ps.name.clone()).collect::<Vec<_>>(),
+      "signature": admin::request_signature(&interaction.request),
+      "mismatches": mismatches.iter().map(|mismatch| json!({
+        "type": mismatch.mismatch_type(),
+        "description": mismatch.description()
+      })).collect::<Vec<_>>()
+    })).collect::<Vec<_>>()
+  })
+}
+
+/// Response to a request, either generated from the pacts or returned by the proxied server
+pub(crate) enum Reply {
+  Pact(HttpResponse),
+  Proxied(reqwest::Response),
+  /// Response streamed as Server-Sent Events, with the delay between the events
+  Events(HttpResponse, Duration)
+}
+
+pub(crate) async fn handle_request(
+  shared: &Shared,
   request: HttpRequest,
-  auto_cors: bool,
-  cors_referrer: bool,
-  sources: Vec<(V4Pact, PactSource)>,
   provider_state: Option<Regex>,
-  empty_provider_states: bool
-) -> HttpResponse {
+  state_params: &HashMap<String, serde_json::Value>
+) -> Reply {
   info! ("===> Received {}", request);
   debug!("     body: '{}'", request.body.display_string());
   debug!("     matching_rules: {:?}", request.matching_rules);
   debug!("     generators: {:?}", request.generators);
-  match find_matching_request(&request, auto_cors, cors_referrer, sources, provider_state,
-                              empty_provider_states).await {
-    Ok(response) => response,
-    Err(msg) => {
-      warn!("{}, sending {}", msg, StatusCode::NOT_FOUND);
-      let mut response = HttpResponse {
-        status: StatusCode::NOT_FOUND.as_u16(),
-        .. HttpResponse::default()
-      };
-      if auto_cors {
-        response.headers = Some(hashmap!{ "Access-Control-Allow-Origin".to_string() => vec!["*".to_string()] })
+  let options = &shared.options;
+  let sources = shared.current_sources();
+  // Interactions without a provider state are always available in a scenario
+  let (provider_state, empty_provider_states) = match (provider_state, &shared.scenario) {
+    (None, Some(scenario)) => (Some(scenario.state_filter()), true),
+    (provider_state, _) => (provider_state, options.empty_provider_states)
+  };
+  let result = find_matching_request(&request, options.auto_cors, options.cors_referer,
+    filter_by_state_params(sources.clone(), state_params, empty_provider_states), provider_state,
+    empty_provider_states, &shared.matching).await;
+  if let (Some(scenario), Some(interaction)) = (&shared.scenario, &result.interaction) {
+    scenario.transition(interaction);
+  }
+  if result.response.is_err() && let Some(recorder) = &shared.recorder {
+    match recorder.forward(&request).await {
+      Ok(response) => {
+        if let Some(journal) = &shared.journal {
+          journal.record(JournalEntry::new(&request, None, &result.candidates, response.status));
+        }
+        return Reply::Pact(response);
+      }
+      Err(err) => error!("{:#}", err)
+    }
+  }
+
+  if result.response.is_err() && let Some(proxy) = &shared.proxy {
+    match proxy.forward(&request).await {
+      Ok(response) => {
+        if let Some(journal) = &shared.journal {
+          journal.record(JournalEntry::new(&request, None, &result.candidates, response.status().as_u16()));
+        }
+        return Reply::Proxied(response);
+      }
+      Err(err) => error!("{:#}", err)
+    }
+  }
+
+  let response = match result.response {
+    Ok(ref response) => response.clone(),
+    Err(ref msg) => {
+      let unmatched = &options.unmatched_response;
+      warn!("{}, sending {}", msg, StatusCode::from_u16(unmatched.status)
+        .map(|status| status.to_string())
+        .unwrap_or_else(|_| unmatched.status.to_string()));
+      let mut response = unmatched.to_response();
+      if options.explain_mismatches && unmatched.body.is_none() {
+        let closest = closest_interactions(&request, &sources, &result.candidates).await;
+        if !response.has_header("Content-Type") {
+          response.add_header("Content-Type", vec![MISMATCH_CONTENT_TYPE]);
+        }
+        response.body = OptionalBody::Present(mismatch_json(msg, &request, &closest).to_string().into(), None, None);
+      }
+      if options.auto_cors && !response.has_header("Access-Control-Allow-Origin") {
+        response.add_header("Access-Control-Allow-Origin", vec!["*"]);
       }
       response
     }
+  };
+
+  if let Some(journal) = &shared.journal {
+    journal.record(JournalEntry::new(&request, result.interaction.as_ref(), &result.candidates, response.status));
+  }
+  let event_delay = result.interaction.as_ref().and_then(|interaction| events::stream_delay(interaction, &response,
+    options.event_delay.unwrap_or(DEFAULT_EVENT_DELAY)));
+  match event_delay {
+    Some(delay) => Reply::Events(response, delay),
+    None => Reply::Pact(response)
   }
 }
 
@@ -398,6 +981,8 @@ mod test {
   use regex::Regex;
 
   use crate::PactSource;
+  use crate::matching::{MatchConfig, MismatchPolicy};
+  use crate::strategy::{MatchStrategy, Selector};
 
   #[tokio::test]
   async fn match_request_finds_the_most_appropriate_response() {
@@ -410,7 +995,7 @@ mod test {
 
     let request1 = HttpRequest::default();
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok().value(interaction1.response));
   }
 
@@ -426,7 +1011,7 @@ mod test {
 
     let request1 = HttpRequest { method: "POST".to_string(), .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_err());
   }
 
@@ -446,7 +1031,7 @@ mod test {
 
     let request1 = HttpRequest { path: "/two".to_string(), .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_err());
   }
 
@@ -465,7 +1050,7 @@ mod test {
         query: Some(hashmap!{ "A".to_string() => vec![ Some("C".to_string()) ] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_err());
   }
 
@@ -504,10 +1089,10 @@ mod test {
     let request4 = HttpRequest { method: "PUT".to_string(), headers: Some(hashmap!{ "Content-Type".to_string() => vec!["application/json".to_string()] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_ok());
-    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_err());
-    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await).to(be_ok());
-    expect!(super::find_matching_request(&request4, false, false, vec![(pact, PactSource::Unknown)], None, false).await).to(be_ok());
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response).to(be_ok());
+    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response).to(be_err());
+    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response).to(be_ok());
+    expect!(super::find_matching_request(&request4, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response).to(be_ok());
   }
 
   #[tokio::test]
@@ -537,10 +1122,81 @@ mod test {
         body: OptionalBody::Present("{\"a\": 1, \"b\": 4, \"c\": 6}".as_bytes().into(), None, None),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact1, PactSource::Unknown), (pact2, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact1, PactSource::Unknown), (pact2, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok().value(interaction2.response));
   }
 
+  #[tokio::test]
+  async fn match_request_selects_by_the_match_strategy() {
+    let interaction1 = SynchronousHttp { request: HttpRequest {
+        body: OptionalBody::Present("{\"a\": 1, \"b\": 2, \"c\": 3}".as_bytes().into(), None, None),
+        .. HttpRequest::default() },
+        response: HttpResponse { status: 200, .. HttpResponse::default() },
+        .. SynchronousHttp::default() };
+    let interaction2 = SynchronousHttp { request: HttpRequest {
+        body: OptionalBody::Present("{\"a\": 2, \"b\": 4, \"c\": 6}".as_bytes().into(), None, None),
+        .. HttpRequest::default() },
+        response: HttpResponse { status: 201, .. HttpResponse::default() },
+        .. SynchronousHttp::default() };
+    let pact = V4Pact {
+      interactions: vec![ interaction1.boxed_v4(), interaction2.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let request = HttpRequest {
+        body: OptionalBody::Present("{\"a\": 1, \"b\": 4, \"c\": 6}".as_bytes().into(), None, None),
+        .. HttpRequest::default() };
+    let sources = vec![(pact, PactSource::Unknown)];
+
+    let config = MatchConfig { selector: Selector::new(MatchStrategy::First), .. MatchConfig::default() };
+    expect!(super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await.response)
+      .to(be_ok().value(interaction1.response.clone()));
+    let config = MatchConfig { selector: Selector::new(MatchStrategy::FewestMismatches), .. MatchConfig::default() };
+    expect!(super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await.response)
+      .to(be_ok().value(interaction2.response.clone()));
+
+    let config = MatchConfig { selector: Selector::new(MatchStrategy::RoundRobin), .. MatchConfig::default() };
+    let mut statuses = vec![];
+    for _ in 0..3 {
+      let result = super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await;
+      statuses.push(result.response.unwrap().status);
+    }
+    expect!(statuses).to(be_equal_to(vec![201, 200, 201]));
+
+    let config = MatchConfig { selector: Selector::new(MatchStrategy::Error), .. MatchConfig::default() };
+    let result = super::find_matching_request(&request, false, false, sources, None, false, &config).await;
+    expect!(result.interaction).to(be_none());
+    let response = result.response.unwrap();
+    expect!(response.status).to(be_equal_to(409));
+    let body: serde_json::Value = serde_json::from_slice(&response.body.value().unwrap()).unwrap();
+    expect!(body["candidates"].as_array().map(|c| c.len())).to(be_some().value(2));
+  }
+
+  #[tokio::test]
+  async fn match_request_with_strict_headers_rejects_header_mismatches() {
+    let interaction = SynchronousHttp { request: HttpRequest {
+        headers: Some(hashmap!{
+          "Authorization".to_string() => vec!["Bearer 1234".to_string()],
+          "Accept".to_string() => vec!["application/json".to_string()]
+        }),
+        .. HttpRequest::default() },
+        .. SynchronousHttp::default() };
+    let pact = V4Pact { interactions: vec![ interaction.boxed_v4() ], .. V4Pact::default() };
+    let sources = vec![(pact, PactSource::Unknown)];
+    let request = HttpRequest {
+      headers: Some(hashmap!{ "Authorization".to_string() => vec!["Bearer 1234".to_string()] }),
+      .. HttpRequest::default()
+    };
+
+    let config = MatchConfig::default();
+    expect!(super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await.response).to(be_ok());
+    let config = MatchConfig { policy: MismatchPolicy::new(false, &["authorization"]), .. MatchConfig::default() };
+    expect!(super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await.response).to(be_ok());
+    let config = MatchConfig { policy: MismatchPolicy::new(false, &["accept"]), .. MatchConfig::default() };
+    expect!(super::find_matching_request(&request, false, false, sources.clone(), None, false, &config).await.response).to(be_err());
+    let config = MatchConfig { policy: MismatchPolicy::new::<&str>(true, &[]), .. MatchConfig::default() };
+    expect!(super::find_matching_request(&request, false, false, sources, None, false, &config).await.response).to(be_err());
+  }
+
   #[tokio::test]
   async fn with_auto_cors_return_200_with_an_option_request() {
     let interaction1 = SynchronousHttp::default();
@@ -553,9 +1209,9 @@ mod test {
         method: "OPTIONS".to_string(),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, true, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, true, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_err());
   }
 
@@ -595,7 +1251,7 @@ mod test {
         query: Some(hashmap!{ "page".to_string() => vec![ Some("3".to_string()) ] }),
         .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
   }
 
@@ -666,15 +1322,15 @@ mod test {
       ] }),
       .. HttpRequest::default() };
 
-    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request1, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_err());
-    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request2, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
-    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request3, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
-    expect!(super::find_matching_request(&request4, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request4, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
-    expect!(super::find_matching_request(&request5, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false).await)
+    expect!(super::find_matching_request(&request5, false, false, vec![(pact.clone(), PactSource::Unknown)], None, false, &MatchConfig::default()).await.response)
       .to(be_ok());
   }
 
@@ -714,15 +1370,15 @@ mod test {
     let request = HttpRequest::default();
 
     expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state one").unwrap()), false).await).to(be_ok().value(response1.clone()));
+      Some(Regex::new("state one").unwrap()), false, &MatchConfig::default()).await.response).to(be_ok().value(response1.clone()));
     expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state two").unwrap()), false).await).to(be_ok().value(response2.clone()));
+      Some(Regex::new("state two").unwrap()), false, &MatchConfig::default()).await.response).to(be_ok().value(response2.clone()));
     expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state three").unwrap()), false).await).to(be_ok().value(response3.clone()));
+      Some(Regex::new("state three").unwrap()), false, &MatchConfig::default()).await.response).to(be_ok().value(response3.clone()));
     expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state four").unwrap()), false).await).to(be_err());
+      Some(Regex::new("state four").unwrap()), false, &MatchConfig::default()).await.response).to(be_err());
     expect!(super::find_matching_request(&request, false, false, vec![(pact.clone(), PactSource::Unknown)],
-      Some(Regex::new("state .*").unwrap()), false).await).to(be_ok().value(response1.clone()));
+      Some(Regex::new("state .*").unwrap()), false, &MatchConfig::default()).await.response).to(be_ok().value(response1.clone()));
   }
 
   #[tokio::test]
@@ -759,10 +1415,39 @@ mod test {
     let request = HttpRequest::default();
 
     expect!(super::find_matching_request(&request, false, false, vec![(pact1, PactSource::Unknown)],
-      Some(Regex::new("any state").unwrap()), true).await).to(be_ok().value(response2.clone()));
+      Some(Regex::new("any state").unwrap()), true, &MatchConfig::default()).await.response).to(be_ok().value(response2.clone()));
 
     expect!(super::find_matching_request(&request, false, false, vec![(pact2, PactSource::Unknown)],
-      Some(Regex::new("any state").unwrap()), true).await).to(be_ok().value(response3.clone()));
+      Some(Regex::new("any state").unwrap()), true, &MatchConfig::default()).await.response).to(be_ok().value(response3.clone()));
+  }
+
+  #[tokio::test]
+  async fn match_request_only_prefers_matching_provider_states_in_scenario_mode() {
+    let empty_state = SynchronousHttp {
+      response: HttpResponse { status: 200, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let matching_state = SynchronousHttp {
+      provider_states: vec![ ProviderState::default("user exists") ],
+      response: HttpResponse { status: 201, .. HttpResponse::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ empty_state.boxed_v4(), matching_state.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let request = HttpRequest::default();
+    let find = |config: MatchConfig| {
+      let pact = pact.clone();
+      let request = request.clone();
+      async move {
+        super::find_matching_request(&request, false, false, vec![(pact, PactSource::Unknown)],
+          Some(Regex::new("user exists").unwrap()), true, &config).await.response.unwrap().status
+      }
+    };
+
+    expect!(find(MatchConfig::default()).await).to(be_equal_to(200));
+    expect!(find(MatchConfig { prefer_matching_states: true, .. MatchConfig::default() }).await).to(be_equal_to(201));
   }
 
   #[tokio::test]
@@ -778,7 +1463,241 @@ mod test {
 
     let request = HttpRequest { headers: Some(hashmap!{ "TEST-X".to_string() => vec!["X, Y".to_string()] }), .. HttpRequest::default() };
 
-    let result = super::find_matching_request(&request, false, false, vec![(pact, PactSource::Unknown)], None, false).await;
+    let result = super::find_matching_request(&request, false, false, vec![(pact, PactSource::Unknown)], None, false, &MatchConfig::default()).await.response;
     expect!(result).to(be_ok().value(interaction.response));
   }
+
+  #[tokio::test]
+  async fn explains_the_closest_interactions_when_no_interaction_matches() {
+    let interaction1 = SynchronousHttp {
+      description: "page one".to_string(),
+      request: HttpRequest {
+        path: "/api".to_string(),
+        query: Some(hashmap!{ "page".to_string() => vec![ Some("1".to_string()) ] }),
+        .. HttpRequest::default()
+      },
+      .. SynchronousHttp::default()
+    };
+    let interaction2 = SynchronousHttp {
+      description: "other path".to_string(),
+      request: HttpRequest { path: "/other".to_string(), .. HttpRequest::default() },
+      .. SynchronousHttp::default()
+    };
+    let pact = V4Pact {
+      interactions: vec![ interaction1.boxed_v4(), interaction2.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let handler = super::ServerHandler::new(vec![(pact, PactSource::Unknown)], super::ServerOptions {
+      explain_mismatches: true,
+      .. super::ServerOptions::default()
+    });
+
+    let request = HttpRequest { path: "/api".to_string(), .. HttpRequest::default() };
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, request, None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(404));
+    let json: serde_json::Value = serde_json::from_slice(&response.body.value().unwrap()).unwrap();
+    let closest = json["closestInteractions"].as_array().unwrap();
+    expect!(closest.len()).to(be_equal_to(1));
+    expect!(closest[0]["description"].as_str()).to(be_some().value("page one"));
+    expect!(closest[0]["mismatches"][0]["type"].as_str()).to(be_some().value("QueryMismatch"));
+
+    let request = HttpRequest { path: "/unknown".to_string(), .. HttpRequest::default() };
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, request, None, &hashmap!{}).await else { panic!("expected a pact response") };
+    let json: serde_json::Value = serde_json::from_slice(&response.body.value().unwrap()).unwrap();
+    expect!(json["closestInteractions"].as_array().unwrap().len()).to(be_equal_to(2));
+  }
+
+  #[tokio::test]
+  async fn unmatched_requests_use_the_configured_response() {
+    let handler = super::ServerHandler::new(vec![], super::ServerOptions {
+      auto_cors: true,
+      explain_mismatches: true,
+      unmatched_response: super::UnmatchedResponse {
+        status: 501,
+        headers: vec![
+          ("X-Stub".to_string(), "unmatched".to_string()),
+          ("X-Stub".to_string(), "again".to_string())
+        ],
+        body: Some("{\"error\":\"not implemented\"}".into())
+      },
+      .. super::ServerOptions::default()
+    });
+
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, HttpRequest::default(), None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(501));
+    expect!(response.headers.clone().unwrap().get("X-Stub").cloned())
+      .to(be_some().value(vec!["unmatched".to_string(), "again".to_string()]));
+    expect!(response.headers.clone().unwrap().get("Access-Control-Allow-Origin").cloned())
+      .to(be_some().value(vec!["*".to_string()]));
+    expect!(response.body.value()).to(be_some().value("{\"error\":\"not implemented\"}".as_bytes()));
+    expect!(response.content_type().map(|ct| ct.to_string())).to(be_some().value("application/json"));
+  }
+
+  #[tokio::test]
+  async fn scenario_selects_interactions_by_the_current_state() {
+    let create = SynchronousHttp {
+      provider_states: vec![ ProviderState::default("no orders") ],
+      request: HttpRequest { method: "POST".to_string(), path: "/orders".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 201, .. HttpResponse::default() },
+      comments: hashmap!{ "nextState".to_string() => serde_json::json!("order created") },
+      .. SynchronousHttp::default() };
+    let get_missing = SynchronousHttp {
+      request: HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 404, .. HttpResponse::default() },
+      .. SynchronousHttp::default() };
+    let get_created = SynchronousHttp {
+      provider_states: vec![ ProviderState::default("order created") ],
+      request: HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() },
+      response: HttpResponse { status: 200, .. HttpResponse::default() },
+      .. SynchronousHttp::default() };
+    let pact = V4Pact {
+      interactions: vec![ create.boxed_v4(), get_missing.boxed_v4(), get_created.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let handler = super::ServerHandler::new(vec![(pact, PactSource::Unknown)], super::ServerOptions {
+      scenario: Some("no orders".to_string()),
+      .. super::ServerOptions::default()
+    });
+    let get = HttpRequest { path: "/orders/1".to_string(), .. HttpRequest::default() };
+    let post = HttpRequest { method: "POST".to_string(), path: "/orders".to_string(), .. HttpRequest::default() };
+
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, get.clone(), None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(404));
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, post.clone(), None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(201));
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, get, None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(200));
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, post, None, &hashmap!{}).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(404));
+  }
+
+  #[tokio::test]
+  async fn filters_interactions_by_provider_state_params() {
+    let user1 = SynchronousHttp {
+      provider_states: vec![ ProviderState { name: "user exists".to_string(), params: hashmap!{ "userId".to_string() => serde_json::json!(1) } } ],
+      response: HttpResponse { status: 201, .. HttpResponse::default() },
+      .. SynchronousHttp::default() };
+    let user42 = SynchronousHttp {
+      provider_states: vec![ ProviderState { name: "user exists".to_string(), params: hashmap!{ "userId".to_string() => serde_json::json!(42) } } ],
+      response: HttpResponse { status: 202, .. HttpResponse::default() },
+      .. SynchronousHttp::default() };
+    let pact = V4Pact {
+      interactions: vec![ user1.boxed_v4(), user42.boxed_v4() ],
+      .. V4Pact::default()
+    };
+    let handler = super::ServerHandler::new(vec![(pact, PactSource::Unknown)], super::ServerOptions {
+      provider_state_params: hashmap!{ "userId".to_string() => serde_json::json!("42") },
+      provider_state_params_header_name: Some("X-Provider-State-Params".to_string()),
+      .. super::ServerOptions::default()
+    });
+
+    let request = HttpRequest::default();
+    let params = super::provider_state_params(&handler.shared.options, &request).unwrap();
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, request, None, &params).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(202));
+
+    let request = HttpRequest {
+      headers: Some(hashmap!{ "x-provider-state-params".to_string() => vec!["{\"userId\": 1}".to_string()] }),
+      .. HttpRequest::default() };
+    let params = super::provider_state_params(&handler.shared.options, &request).unwrap();
+    let super::Reply::Pact(response) = super::handle_request(&handler.shared, request, None, &params).await else { panic!("expected a pact response") };
+    expect!(response.status).to(be_equal_to(201));
+
+    let request = HttpRequest {
+      headers: Some(hashmap!{ "x-provider-state-params".to_string() => vec!["userId=1".to_string()] }),
+      .. HttpRequest::default() };
+    expect!(super::provider_state_params(&handler.shared.options, &request)).to(be_err());
+  }
+
+  #[test]
+  fn provider_state_header_values_are_validated() {
+    let header = http::HeaderValue::from_static("state (one");
+    expect!(super::provider_state_from_header("X-State", &header, false)).to(be_err());
+    let regex = super::provider_state_from_header("X-State", &header, true).unwrap();
+    expect!(regex.is_match("state (one")).to(be_true());
+
+    let header = http::HeaderValue::from_bytes("état".as_bytes()).unwrap();
+    expect!(super::provider_state_from_header("X-State", &header, false)).to(be_err());
+
+    let header = http::HeaderValue::from_static("state .*");
+    let regex = super::provider_state_from_header("X-State", &header, false).unwrap();
+    expect!(regex.is_match("state two")).to(be_true());
+  }
+
+  #[tokio::test]
+  async fn accept_connections_returns_once_shutdown_is_signalled() {
+    let handler = super::ServerHandler::new(vec![], super::ServerOptions::default());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(super::accept_connections(handler, listener, None, async move {
+      let _ = rx.await;
+    }, std::time::Duration::from_secs(1)));
+
+    tx.send(()).unwrap();
+    expect!(server.await.unwrap()).to(be_ok());
+  }
+
+  /// Upstream that responds to a single request after the delay
+  async fn slow_upstream(delay: std::time::Duration) -> reqwest::Url {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = reqwest::Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buffer = [0; 1024];
+      let _ = stream.read(&mut buffer).await;
+      tokio::time::sleep(delay).await;
+      let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow").await;
+    });
+    url
+  }
+
+  /// Starts a server forwarding requests to the upstream, and sends a request to it
+  async fn start_slow_request(
+    upstream: reqwest::Url,
+    shutdown_timeout: std::time::Duration
+  ) -> (tokio::sync::oneshot::Sender<()>, tokio::task::JoinHandle<Result<(), std::process::ExitCode>>, tokio::task::JoinHandle<reqwest::Result<String>>) {
+    let handler = super::ServerHandler::new(vec![], super::ServerOptions {
+      proxy_url: Some(upstream),
+      .. super::ServerOptions::default()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/slow", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(super::accept_connections(handler, listener, None, async move {
+      let _ = rx.await;
+    }, shutdown_timeout));
+    let request = tokio::spawn(async move {
+      reqwest::get(url).await?.text().await
+    });
+    // Let the request reach the upstream before signalling shutdown
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    (tx, server, request)
+  }
+
+  #[tokio::test]
+  async fn in_flight_requests_complete_after_shutdown_is_signalled() {
+    let upstream = slow_upstream(std::time::Duration::from_millis(300)).await;
+    let (tx, server, request) = start_slow_request(upstream, std::time::Duration::from_secs(5)).await;
+
+    tx.send(()).unwrap();
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), server).await;
+    expect!(result.is_ok()).to(be_true());
+    expect!(request.await.unwrap()).to(be_ok().value("slow".to_string()));
+  }
+
+  #[tokio::test]
+  async fn connections_open_past_the_shutdown_timeout_are_closed() {
+    let upstream = slow_upstream(std::time::Duration::from_secs(30)).await;
+    let (tx, server, request) = start_slow_request(upstream, std::time::Duration::from_millis(200)).await;
+
+    let started = std::time::Instant::now();
+    tx.send(()).unwrap();
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), server).await;
+    expect!(result.is_ok()).to(be_true());
+    expect!(started.elapsed() < std::time::Duration::from_secs(2)).to(be_true());
+    let response = tokio::time::timeout(std::time::Duration::from_secs(5), request).await;
+    expect!(response.unwrap().unwrap()).to(be_err());
+  }
 }
@@ -0,0 +1,335 @@
+//! Strategies for selecting the interaction to respond with when several interactions match a
+//! request
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use pact_matching::Mismatch;
+use pact_models::prelude::v4::SynchronousHttp;
+use pact_models::v4::http_parts::HttpRequest;
+
+use crate::admin::request_signature;
+
+/// How to select the interaction when more than one matches a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchStrategy {
+  /// The first matching interaction, in the order the pacts were loaded
+  First,
+  /// The interaction with the fewest mismatches, then the first loaded
+  #[default]
+  FewestMismatches,
+  /// The interaction with the fewest mismatches, then the most satisfied matching rules, headers
+  /// and query parameters
+  MostSpecific,
+  /// Each matching interaction in turn, for repeated requests with the same method, path and query
+  RoundRobin,
+  /// Return a conflict response listing the matching interactions
+  Error
+}
+
+impl MatchStrategy {
+  /// Names of all the strategies, as used on the command line
+  pub const NAMES: [&'static str; 5] = ["first", "fewest-mismatches", "most-specific", "round-robin", "error"];
+
+  /// Sort key for a matching interaction. Interactions with lower keys are preferred, and
+  /// interactions with equal keys keep the order the pacts were loaded in.
+  pub(crate) fn rank(&self, interaction: &SynchronousHttp, mismatches: &[Mismatch]) -> (usize, Reverse<usize>) {
+    match self {
+      MatchStrategy::First => (0, Reverse(0)),
+      MatchStrategy::MostSpecific => (mismatches.len(), Reverse(satisfied(&interaction.request, mismatches))),
+      _ => (mismatches.len(), Reverse(0))
+    }
+  }
+}
+
+impl Display for MatchStrategy {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let name = match self {
+      MatchStrategy::First => "first",
+      MatchStrategy::FewestMismatches => "fewest-mismatches",
+      MatchStrategy::MostSpecific => "most-specific",
+      MatchStrategy::RoundRobin => "round-robin",
+      MatchStrategy::Error => "error"
+    };
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for MatchStrategy {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "first" => Ok(MatchStrategy::First),
+      "fewest-mismatches" => Ok(MatchStrategy::FewestMismatches),
+      "most-specific" => Ok(MatchStrategy::MostSpecific),
+      "round-robin" => Ok(MatchStrategy::RoundRobin),
+      "error" => Ok(MatchStrategy::Error),
+      _ => Err(anyhow!("'{}' is not a valid match strategy, expected one of {}", s, MatchStrategy::NAMES.join(", ")))
+    }
+  }
+}
+
+/// Number of matching rules, headers and query parameters the request of an interaction expects
+/// that the request satisfied
+fn satisfied(request: &HttpRequest, mismatches: &[Mismatch]) -> usize {
+  specificity(request).saturating_sub(mismatches.len())
+}
+
+/// Number of matching rules, headers and query parameters the request of an interaction expects
+fn specificity(request: &HttpRequest) -> usize {
+  let rules = request.matching_rules.rules.values()
+    .flat_map(|category| category.rules.values())
+    .map(|rules| rules.rules.len())
+    .sum::<usize>();
+  let headers = request.headers.as_ref().map(|headers| headers.len()).unwrap_or_default();
+  let query = request.query.as_ref().map(|query| query.len()).unwrap_or_default();
+  rules + headers + query
+}
+
+/// Outcome of selecting from the matching interactions
+#[derive(Debug, Clone)]
+pub(crate) enum Selection {
+  /// No interaction matched
+  None,
+  /// The interaction to respond with
+  Selected(Box<SynchronousHttp>),
+  /// Several interactions matched, and the strategy does not choose between them
+  Ambiguous(Vec<SynchronousHttp>)
+}
+
+/// How repeated requests with the same method, path and query move through the matching
+/// interactions, in the order they were loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceMode {
+  /// Go back to the first interaction after the last one
+  Cycle,
+  /// Keep using the last interaction once it is reached
+  Advance
+}
+
+impl SequenceMode {
+  /// Names of all the sequence modes, as used on the command line
+  pub const NAMES: [&'static str; 2] = ["cycle", "advance"];
+}
+
+impl Display for SequenceMode {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      SequenceMode::Cycle => write!(f, "cycle"),
+      SequenceMode::Advance => write!(f, "advance")
+    }
+  }
+}
+
+impl FromStr for SequenceMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "cycle" => Ok(SequenceMode::Cycle),
+      "advance" => Ok(SequenceMode::Advance),
+      _ => Err(anyhow!("'{}' is not a valid sequence mode, expected one of {}", s, SequenceMode::NAMES.join(", ")))
+    }
+  }
+}
+
+/// Selects the interaction to respond with, keeping track of the position in the sequence of
+/// matching interactions for each request signature
+#[derive(Debug, Default)]
+pub struct Selector {
+  strategy: MatchStrategy,
+  sequence: Option<SequenceMode>,
+  positions: Mutex<HashMap<String, usize>>
+}
+
+impl Selector {
+  /// Creates a selector for the strategy
+  pub fn new(strategy: MatchStrategy) -> Self {
+    Selector { strategy, sequence: None, positions: Mutex::new(HashMap::new()) }
+  }
+
+  /// Creates a selector that moves through the matching interactions in sequence, in the order
+  /// they were loaded
+  pub fn sequenced(mode: SequenceMode) -> Self {
+    Selector { strategy: MatchStrategy::First, sequence: Some(mode), positions: Mutex::new(HashMap::new()) }
+  }
+
+  /// Strategy used to order the matching interactions
+  pub fn strategy(&self) -> MatchStrategy {
+    self.strategy
+  }
+
+  /// Sequence mode, if the matching interactions are used in sequence
+  pub fn sequence(&self) -> Option<SequenceMode> {
+    self.sequence
+  }
+
+  /// Number of requests made so far for each request signature that is sequenced
+  pub fn positions(&self) -> HashMap<String, usize> {
+    self.positions.lock().unwrap().clone()
+  }
+
+  /// Starts all the sequences again from the first interaction
+  pub fn reset(&self) {
+    self.positions.lock().unwrap().clear();
+  }
+
+  /// Selects from the matching interactions, which must already be sorted by their rank
+  pub(crate) fn select(&self, request: &HttpRequest, matches: &[(SynchronousHttp, Vec<Mismatch>)]) -> Selection {
+    match (self.strategy, self.sequence, matches) {
+      (_, _, []) => Selection::None,
+      (_, Some(mode), _) => {
+        let position = self.next_position(request);
+        let index = match mode {
+          SequenceMode::Cycle => position % matches.len(),
+          SequenceMode::Advance => position.min(matches.len() - 1)
+        };
+        Selection::Selected(Box::new(matches[index].0.clone()))
+      }
+      (_, _, [(interaction, _)]) => Selection::Selected(Box::new(interaction.clone())),
+      (MatchStrategy::Error, _, _) => Selection::Ambiguous(matches.iter().map(|(i, _)| i.clone()).collect()),
+      (MatchStrategy::RoundRobin, _, _) => {
+        let position = self.next_position(request);
+        Selection::Selected(Box::new(matches[position % matches.len()].0.clone()))
+      }
+      _ => Selection::Selected(Box::new(matches[0].0.clone()))
+    }
+  }
+
+  /// Current position for the request signature, moving it on for the next request
+  fn next_position(&self, request: &HttpRequest) -> usize {
+    let mut positions = self.positions.lock().unwrap();
+    let position = positions.entry(request_signature(request)).or_default();
+    let current = *position;
+    *position = position.saturating_add(1);
+    current
+  }
+}
+
+impl Display for Selector {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.sequence {
+      Some(mode) => write!(f, "the {} sequence", mode),
+      None => write!(f, "the {} match strategy", self.strategy)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use maplit::hashmap;
+  use pact_models::matchingrules;
+  use pact_models::matchingrules::MatchingRule;
+
+  use super::*;
+
+  fn interaction(description: &str) -> SynchronousHttp {
+    SynchronousHttp { description: description.to_string(), .. SynchronousHttp::default() }
+  }
+
+  fn description(selection: Selection) -> String {
+    match selection {
+      Selection::Selected(interaction) => interaction.description,
+      _ => panic!("Expected an interaction to be selected, got {:?}", selection)
+    }
+  }
+
+  #[test]
+  fn parses_the_strategy_names() {
+    for name in MatchStrategy::NAMES {
+      expect!(MatchStrategy::from_str(name).unwrap().to_string()).to(be_equal_to(name));
+    }
+    expect!(MatchStrategy::from_str("last")).to(be_err());
+  }
+
+  #[test]
+  fn most_specific_ranks_interactions_with_more_expectations_first() {
+    let general = interaction("general");
+    let specific = SynchronousHttp {
+      request: HttpRequest {
+        headers: Some(hashmap!{ "Accept".to_string() => vec!["application/json".to_string()] }),
+        matching_rules: matchingrules!{ "path" => { "" => [ MatchingRule::Regex("/users/\\d+".to_string()) ] } },
+        .. HttpRequest::default()
+      },
+      .. interaction("specific")
+    };
+    let strategy = MatchStrategy::MostSpecific;
+    expect!(strategy.rank(&specific, &[]) < strategy.rank(&general, &[])).to(be_true());
+    expect!(MatchStrategy::First.rank(&specific, &[]) == MatchStrategy::First.rank(&general, &[])).to(be_true());
+  }
+
+  #[test]
+  fn most_specific_prefers_fully_matching_interactions_over_ones_expecting_more() {
+    let header = |name: &str| (name.to_string(), vec!["1".to_string()]);
+    let tight = SynchronousHttp {
+      request: HttpRequest { headers: Some(vec![ header("X-Tenant") ].into_iter().collect()), .. HttpRequest::default() },
+      .. interaction("tight")
+    };
+    let loose = SynchronousHttp {
+      request: HttpRequest {
+        headers: Some(vec![ header("X-Tenant"), header("X-Region"), header("X-Trace"), header("X-Version") ].into_iter().collect()),
+        .. HttpRequest::default()
+      },
+      .. interaction("loose")
+    };
+    let mismatch = |key: &str| Mismatch::HeaderMismatch {
+      key: key.to_string(),
+      expected: "1".to_string(),
+      actual: "".to_string(),
+      mismatch: format!("Expected a header '{}' but was missing", key)
+    };
+    let loose_mismatches = vec![ mismatch("X-Region"), mismatch("X-Trace") ];
+
+    // Counting only the expected items would rank the loose interaction first (4 > 1)
+    expect!(specificity(&loose.request) > specificity(&tight.request)).to(be_true());
+    let strategy = MatchStrategy::MostSpecific;
+    expect!(strategy.rank(&tight, &[]) < strategy.rank(&loose, &loose_mismatches)).to(be_true());
+    expect!(strategy.rank(&loose, &[]) < strategy.rank(&tight, &[])).to(be_true());
+  }
+
+  #[test]
+  fn round_robin_cycles_through_the_matches_per_request() {
+    let selector = Selector::new(MatchStrategy::RoundRobin);
+    let matches = vec![(interaction("one"), vec![]), (interaction("two"), vec![])];
+    let users = HttpRequest { path: "/users".to_string(), .. HttpRequest::default() };
+    let orders = HttpRequest { path: "/orders".to_string(), .. HttpRequest::default() };
+
+    expect!(description(selector.select(&users, &matches))).to(be_equal_to("one"));
+    expect!(description(selector.select(&users, &matches))).to(be_equal_to("two"));
+    expect!(description(selector.select(&orders, &matches))).to(be_equal_to("one"));
+    expect!(description(selector.select(&users, &matches))).to(be_equal_to("one"));
+  }
+
+  #[test]
+  fn sequences_cycle_or_stop_at_the_last_match() {
+    let matches = vec![(interaction("pending"), vec![]), (interaction("done"), vec![])];
+    let request = HttpRequest { path: "/jobs/1".to_string(), .. HttpRequest::default() };
+
+    let selector = Selector::sequenced(SequenceMode::Advance);
+    let selected = (0..3).map(|_| description(selector.select(&request, &matches))).collect::<Vec<_>>();
+    expect!(selected).to(be_equal_to(vec!["pending".to_string(), "done".to_string(), "done".to_string()]));
+    expect!(selector.positions()).to(be_equal_to(hashmap!{ "GET /jobs/1".to_string() => 3 }));
+    selector.reset();
+    expect!(description(selector.select(&request, &matches))).to(be_equal_to("pending"));
+
+    let selector = Selector::sequenced(SequenceMode::Cycle);
+    let selected = (0..3).map(|_| description(selector.select(&request, &matches))).collect::<Vec<_>>();
+    expect!(selected).to(be_equal_to(vec!["pending".to_string(), "done".to_string(), "pending".to_string()]));
+  }
+
+  #[test]
+  fn error_strategy_reports_ambiguous_matches() {
+    let selector = Selector::new(MatchStrategy::Error);
+    let request = HttpRequest::default();
+    let matches = vec![(interaction("one"), vec![]), (interaction("two"), vec![])];
+    expect!(matches!(selector.select(&request, &matches), Selection::Ambiguous(ref i) if i.len() == 2)).to(be_true());
+    expect!(description(selector.select(&request, &matches[..1]))).to(be_equal_to("one"));
+    expect!(matches!(selector.select(&request, &[]), Selection::None)).to(be_true());
+  }
+}
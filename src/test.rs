@@ -2,7 +2,12 @@ use expectest::prelude::*;
 
 use crate::build_args;
 
-use super::{integer_value, regex_value};
+use pact_plugin_driver::plugin_models::{PluginDependency, PluginDependencyType};
+use serde_json::json;
+
+use super::{bound_address_json, header_value, host_value, integer_value, method_value, regex_value, report_bound_address, state_param_value};
+use crate::LocalAddress;
+use crate::plugins::PluginMockServer;
 
 #[test]
 fn verify_cli() {
@@ -22,3 +27,105 @@ fn validates_regex_value() {
     expect!(regex_value("\\d+")).to(be_ok());
     expect!(regex_value("[")).to(be_err());
 }
+
+#[test]
+fn validates_host_value() {
+    expect!(host_value("127.0.0.1")).to(be_ok());
+    expect!(host_value("::1")).to(be_ok());
+    expect!(host_value("localhost")).to(be_err());
+}
+
+#[test]
+fn validates_header_value() {
+    expect!(header_value("X-Error: true")).to(be_ok().value(("X-Error".to_string(), "true".to_string())));
+    expect!(header_value("Retry-After:")).to(be_ok().value(("Retry-After".to_string(), "".to_string())));
+    expect!(header_value("X-Error")).to(be_err());
+    expect!(header_value(": true")).to(be_err());
+}
+
+#[test]
+fn validates_method_value() {
+    expect!(method_value("query")).to(be_ok().value("QUERY".to_string()));
+    expect!(method_value("*")).to(be_ok().value("*".to_string()));
+    expect!(method_value("GET POST")).to(be_err());
+    expect!(method_value("")).to(be_err());
+}
+
+#[test]
+fn validates_state_param_value() {
+    expect!(state_param_value("userId=42")).to(be_ok().value(("userId".to_string(), serde_json::json!(42))));
+    expect!(state_param_value("name=Mary")).to(be_ok().value(("name".to_string(), serde_json::json!("Mary"))));
+    expect!(state_param_value("=42")).to(be_err());
+    expect!(state_param_value("userId")).to(be_err());
+}
+
+#[test]
+fn writes_the_bound_port_to_the_port_file() {
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-port-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("port.json");
+    std::fs::write(dir.join("port.tmp"), "unrelated").unwrap();
+    let matches = build_args()
+      .try_get_matches_from(vec!["pact-stub-server", "-f", "pact.json", "--port-file", path.to_str().unwrap()])
+      .unwrap();
+
+    let result = report_bound_address(&matches, &LocalAddress::Tcp("127.0.0.1:1234".parse().unwrap()), &[]);
+    let contents = std::fs::read_to_string(&path);
+    let sibling = std::fs::read_to_string(dir.join("port.tmp"));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    expect!(result.is_ok()).to(be_true());
+    expect!(contents).to(be_ok().value("1234".to_string()));
+    expect!(sibling).to(be_ok().value("unrelated".to_string()));
+}
+
+#[test]
+fn reports_the_ports_of_plugin_mock_servers() {
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-plugin-port-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("port.json");
+    let matches = build_args()
+      .try_get_matches_from(vec!["pact-stub-server", "-f", "pact.json", "--port-file", path.to_str().unwrap()])
+      .unwrap();
+    let address = LocalAddress::Tcp("127.0.0.1:1234".parse().unwrap());
+    let plugin_servers = vec![PluginMockServer {
+      transport: "grpc".to_string(),
+      pact: ("area-consumer".to_string(), "area-provider".to_string()),
+      plugin: PluginDependency {
+        name: "protobuf".to_string(),
+        version: Some("0.5.4".to_string()),
+        dependency_type: PluginDependencyType::Plugin
+      },
+      key: "1234-abcd".to_string(),
+      port: 5678,
+      url: "tcp://127.0.0.1:5678".to_string()
+    }];
+
+    let result = report_bound_address(&matches, &address, &plugin_servers);
+    let contents = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    expect!(result.is_ok()).to(be_true());
+    expect!(contents).to(be_ok().value("1234".to_string()));
+    expect!(bound_address_json(&address, &plugin_servers)).to(be_equal_to(json!({
+      "port": 1234,
+      "host": "127.0.0.1",
+      "plugins": [{
+        "transport": "grpc",
+        "plugin": "protobuf",
+        "consumer": "area-consumer",
+        "provider": "area-provider",
+        "port": 5678,
+        "url": "tcp://127.0.0.1:5678"
+      }]
+    })));
+    expect!(bound_address_json(&address, &[])).to(be_equal_to(json!({ "port": 1234, "host": "127.0.0.1" })));
+}
+
+#[test]
+fn strict_header_names_must_not_be_empty() {
+    let args = |name: &str| build_args()
+      .try_get_matches_from(vec!["pact-stub-server", "-f", "pact.json", "--strict-header", name]);
+    expect!(args("Authorization").is_ok()).to(be_true());
+    expect!(args("").is_err()).to(be_true());
+}
@@ -0,0 +1,98 @@
+//! Functions relating to serving requests over HTTPS
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use rustls::ServerConfig;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::pki_types::pem::PemObject;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+/// Source of the certificate and private key used for the HTTPS listener
+#[derive(Debug, Clone)]
+pub enum TlsConfig {
+  /// Load the certificate chain and private key from PEM files
+  Pem {
+    /// Certificate chain file
+    cert: PathBuf,
+    /// Private key file
+    key: PathBuf
+  },
+  /// Generate a throwaway self-signed certificate at startup
+  SelfSigned
+}
+
+impl TlsConfig {
+  /// Creates the TLS acceptor to wrap accepted connections with. HTTP/2 and HTTP/1.1 are
+  /// offered via ALPN.
+  pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+    let (certs, key) = match self {
+      TlsConfig::Pem { cert, key } => {
+        debug!("Loading TLS certificate from {:?} and private key from {:?}", cert, key);
+        let certs = CertificateDer::pem_file_iter(cert)
+          .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+          .with_context(|| format!("Failed to load TLS certificate from {:?}", cert))?;
+        if certs.is_empty() {
+          return Err(anyhow!("No certificates found in {:?}", cert));
+        }
+        let key = PrivateKeyDer::from_pem_file(key)
+          .with_context(|| format!("Failed to load TLS private key from {:?}", key))?;
+        (certs, key)
+      }
+      TlsConfig::SelfSigned => self_signed_certificate()?
+    };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+      .with_safe_default_protocol_versions()?
+      .with_no_client_auth()
+      .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+  }
+}
+
+fn self_signed_certificate() -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+  warn!("Generating a self-signed certificate for localhost. Clients will need to disable certificate validation");
+  let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()])?;
+  let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+  Ok((vec![certified.cert.der().clone()], key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use expectest::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn self_signed_acceptor_negotiates_h2_and_http1() {
+    let acceptor = TlsConfig::SelfSigned.acceptor().unwrap();
+    expect!(acceptor.config().alpn_protocols.clone()).to(be_equal_to(vec![b"h2".to_vec(), b"http/1.1".to_vec()]));
+  }
+
+  #[test]
+  fn pem_acceptor_loads_the_certificate_and_key_files() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("pact-stub-server-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+    let result = TlsConfig::Pem { cert, key }.acceptor();
+    fs::remove_dir_all(&dir).unwrap();
+    expect!(result.is_ok()).to(be_true());
+  }
+
+  #[test]
+  fn pem_acceptor_fails_with_missing_files() {
+    let config = TlsConfig::Pem { cert: PathBuf::from("/does/not/exist.pem"), key: PathBuf::from("/does/not/exist.key") };
+    expect!(config.acceptor().is_err()).to(be_true());
+  }
+}
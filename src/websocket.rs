@@ -0,0 +1,259 @@
+//! WebSocket connections that the contents of asynchronous message interactions are pushed to.
+//! Messages are pushed when triggered through the admin API, or on a schedule.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use hyper::Request as HyperRequest;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use maplit::hashmap;
+use pact_models::bodies::OptionalBody;
+use pact_models::http_parts::HttpPart;
+use pact_models::v4::http_parts::HttpResponse;
+use pact_models::v4::message_parts::MessageContents;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tracing::{debug, info, warn};
+
+use crate::admin::json_response;
+use crate::messages;
+use crate::server::Shared;
+
+/// Number of pushed messages buffered for a client that is slow to receive them
+const CLIENT_BUFFER_SIZE: usize = 64;
+
+/// Version of the WebSocket protocol supported (RFC 6455)
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Pushes messages to the clients connected to the WebSocket path
+#[derive(Debug)]
+pub struct MessagePush {
+  path: String,
+  tx: broadcast::Sender<Message>
+}
+
+impl MessagePush {
+  /// Accepts WebSocket connections on the path
+  pub fn new<S: Into<String>>(path: S) -> Self {
+    let (tx, _) = broadcast::channel(CLIENT_BUFFER_SIZE);
+    MessagePush { path: format!("/{}", path.into().trim_start_matches('/')), tx }
+  }
+
+  /// Path WebSocket connections are accepted on
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
+  /// Number of connected clients
+  pub fn clients(&self) -> usize {
+    self.tx.receiver_count()
+  }
+
+  /// If the request is a WebSocket upgrade request for the path
+  pub fn is_upgrade<B>(&self, request: &HyperRequest<B>) -> bool {
+    let header_contains = |name: &str, token: &str| request.headers().get_all(name).iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .any(|value| value.trim().eq_ignore_ascii_case(token));
+    request.uri().path() == self.path && header_contains("upgrade", "websocket") &&
+      header_contains("connection", "upgrade")
+  }
+
+  /// Completes the WebSocket handshake for the upgrade request. Once the connection is upgraded,
+  /// pushed messages are sent to the client until it disconnects or `stopping` is set.
+  pub fn upgrade<B>(&self, mut request: HyperRequest<B>, stopping: watch::Receiver<bool>) -> HttpResponse {
+    if request.headers().get("sec-websocket-version").map(|version| version.as_bytes()) != Some(WEBSOCKET_VERSION.as_bytes()) {
+      let mut response = json_response(426, serde_json::json!({ "error": "Only version 13 of the WebSocket protocol is supported" }));
+      response.add_header("Sec-WebSocket-Version", vec![WEBSOCKET_VERSION]);
+      return response;
+    }
+    let Some(key) = request.headers().get("sec-websocket-key").map(|key| key.as_bytes().to_vec()) else {
+      return json_response(400, serde_json::json!({ "error": "WebSocket upgrade request has no Sec-WebSocket-Key header" }));
+    };
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(serve_client(on_upgrade, self.tx.subscribe(), stopping));
+    HttpResponse {
+      status: 101,
+      headers: Some(hashmap!{
+        "Connection".to_string() => vec!["Upgrade".to_string()],
+        "Upgrade".to_string() => vec!["websocket".to_string()],
+        "Sec-WebSocket-Accept".to_string() => vec![derive_accept_key(&key)]
+      }),
+      .. HttpResponse::default()
+    }
+  }
+
+  /// Sends the message contents to the connected clients, returning the number of clients
+  pub fn push(&self, description: &str, contents: &MessageContents) -> usize {
+    let clients = self.tx.send(message_frame(contents)).unwrap_or_default();
+    info!("Pushed message '{}' to {} WebSocket client(s)", description, clients);
+    clients
+  }
+}
+
+/// Frame for the message contents. Contents that are valid UTF-8 are sent as a text frame.
+pub fn message_frame(contents: &MessageContents) -> Message {
+  match &contents.contents {
+    OptionalBody::Present(body, ..) => match std::str::from_utf8(body) {
+      Ok(text) => Message::text(text),
+      Err(_) => Message::binary(body.clone())
+    },
+    _ => Message::text("")
+  }
+}
+
+async fn serve_client(on_upgrade: OnUpgrade, mut rx: broadcast::Receiver<Message>, mut stopping: watch::Receiver<bool>) {
+  let upgraded = match on_upgrade.await {
+    Ok(upgraded) => upgraded,
+    Err(err) => {
+      warn!("WebSocket upgrade failed: {}", err);
+      return;
+    }
+  };
+  let mut stream = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+  debug!("WebSocket client connected");
+  loop {
+    tokio::select! {
+      message = rx.recv() => match message {
+        Ok(message) => if let Err(err) = stream.send(message).await {
+          debug!("Failed to send to the WebSocket client: {}", err);
+          break;
+        },
+        Err(RecvError::Lagged(count)) => warn!("WebSocket client missed {} message(s)", count),
+        Err(RecvError::Closed) => {
+          let _ = stream.close(None).await;
+          break;
+        }
+      },
+      _ = async { let _ = stopping.wait_for(|stopping| *stopping).await; } => {
+        let _ = stream.close(Some(CloseFrame { code: CloseCode::Away, reason: "Server is shutting down".into() })).await;
+        break;
+      },
+      frame = stream.next() => match frame {
+        Some(Ok(Message::Close(_))) | None => break,
+        Some(Err(err)) => {
+          debug!("WebSocket connection failed: {}", err);
+          break;
+        }
+        Some(Ok(_)) => {}
+      }
+    }
+  }
+  debug!("WebSocket client disconnected");
+}
+
+/// Pushes the asynchronous message interactions to the connected clients in turn, one every
+/// interval, with their generators applied
+pub(crate) async fn push_on_schedule(shared: Arc<Shared>, interval: Duration) {
+  let Some(push) = shared.websocket.as_ref() else { return };
+  info!("Pushing a message to WebSocket clients every {:?}", interval);
+  let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+  let mut next = 0;
+  loop {
+    ticker.tick().await;
+    if push.clients() == 0 {
+      continue;
+    }
+    let messages = messages::async_messages(&shared.current_sources());
+    if messages.is_empty() {
+      continue;
+    }
+    let message = &messages[next % messages.len()];
+    next += 1;
+    let contents = messages::generate_contents(message).await;
+    push.push(&message.description, &contents);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use expectest::prelude::*;
+  use pact_models::content_types::JSON;
+  use pact_models::prelude::v4::V4Pact;
+  use pact_models::v4::async_message::AsynchronousMessage;
+  use pact_models::v4::interaction::V4Interaction;
+  use serde_json::json;
+
+  use crate::builder::StubServerBuilder;
+
+  use super::*;
+
+  #[test]
+  fn only_upgrade_requests_for_the_path_are_accepted() {
+    let push = MessagePush::new("events");
+    let request = |path: &str, upgrade: &str| HyperRequest::get(path)
+      .header("Upgrade", upgrade)
+      .header("Connection", "keep-alive, Upgrade")
+      .body(())
+      .unwrap();
+
+    expect!(push.path()).to(be_equal_to("/events"));
+    expect!(push.is_upgrade(&request("/events", "websocket"))).to(be_true());
+    expect!(push.is_upgrade(&request("/events", "WebSocket"))).to(be_true());
+    expect!(push.is_upgrade(&request("/orders", "websocket"))).to(be_false());
+    expect!(push.is_upgrade(&request("/events", "h2c"))).to(be_false());
+    expect!(push.is_upgrade(&HyperRequest::get("/events").body(()).unwrap())).to(be_false());
+  }
+
+  #[test]
+  fn rejects_unsupported_websocket_versions() {
+    let push = MessagePush::new("/events");
+    let request = HyperRequest::get("/events")
+      .header("Upgrade", "websocket")
+      .header("Connection", "Upgrade")
+      .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+      .header("Sec-WebSocket-Version", "8")
+      .body(())
+      .unwrap();
+
+    let response = push.upgrade(request, watch::channel(false).1);
+    expect!(response.status).to(be_equal_to(426));
+    expect!(response.lookup_header_value("Sec-WebSocket-Version")).to(be_some().value("13"));
+  }
+
+  #[tokio::test]
+  async fn pushes_triggered_messages_to_connected_clients() {
+    let message = AsynchronousMessage {
+      description: "order created".to_string(),
+      contents: MessageContents {
+        contents: OptionalBody::Present(json!({ "id": 1 }).to_string().into(), Some(JSON.clone()), None),
+        .. MessageContents::default()
+      },
+      .. AsynchronousMessage::default()
+    };
+    let server = StubServerBuilder::new()
+      .with_pact(V4Pact { interactions: vec![ message.boxed_v4() ], .. V4Pact::default() })
+      .host("127.0.0.1".parse().unwrap())
+      .admin_prefix("/__pact")
+      .websocket_path("/events")
+      .start()
+      .await
+      .unwrap();
+    let url = server.url().unwrap();
+    let (mut client, _) = tokio_tungstenite::connect_async(url.replace("http://", "ws://") + "/events")
+      .await
+      .unwrap();
+
+    let response = reqwest::Client::new().post(format!("{}/__pact/trigger", url))
+      .json(&json!({ "description": "order created" }))
+      .send()
+      .await
+      .unwrap();
+    expect!(response.status().as_u16()).to(be_equal_to(200));
+    let frame = client.next().await.unwrap().unwrap();
+    expect!(frame.to_text().unwrap()).to(be_equal_to(json!({ "id": 1 }).to_string()));
+
+    let shutdown = tokio::spawn(server.shutdown());
+    let frame = client.next().await.unwrap().unwrap();
+    expect!(matches!(frame, Message::Close(Some(ref close)) if close.code == CloseCode::Away)).to(be_true());
+    drop(client);
+    shutdown.await.unwrap().unwrap();
+  }
+}